@@ -0,0 +1,262 @@
+use hyper::server::{conn::AddrIncoming, Builder};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::info;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use vega_protobufs::vega::market::TradingMode;
+
+use crate::metrics::{write_metric, METRICS};
+use crate::reference::{RefPrice, Source};
+use crate::{strategy2::Decision, vega_store2::VegaStore};
+
+/// State of one of the markets traded by the bot.
+#[derive(Clone)]
+pub struct MarketState {
+    pub market: String,
+    pub store: Arc<Mutex<VegaStore>>,
+    pub rp: Arc<Mutex<RefPrice>>,
+    // the sources aggregated into the reference price
+    pub sources: Vec<Source>,
+    pub last_decision: Arc<Mutex<Option<Decision>>>,
+    pub pubkeys: Vec<String>,
+}
+
+#[derive(Clone)]
+struct State {
+    markets: Vec<MarketState>,
+}
+
+/// Bind the address of the status API, fails if it is not available.
+pub fn bind(addr: SocketAddr) -> Result<Builder<AddrIncoming>, hyper::Error> {
+    let server = Server::try_bind(&addr)?;
+    info!("status API bound to: {}", addr);
+    return Ok(server);
+}
+
+/// Serve the status of the bot over HTTP on the bound address, the JSON
+/// routes return an object with an entry per vega market ID:
+/// - /ref-price: the reference price, and the price of each source
+/// - /market-data: the vega market data
+/// - /positions: the positions of the wallets
+/// - /fills: the last fills of the wallets, most recent last
+/// - /decision: the outcome of the last strategy cycle
+/// - /health: the age of the reference price and the state of the datanode streams
+/// - /metrics: the metrics of the bot, in the prometheus text format
+pub async fn start(
+    server: Builder<AddrIncoming>,
+    markets: Vec<MarketState>,
+) -> Result<(), hyper::Error> {
+    let state = State { markets };
+
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(handle(req, &state)) }
+            }))
+        }
+    });
+
+    info!("starting status API");
+    return server.serve(make_svc).await;
+}
+
+fn handle(req: Request<Body>, state: &State) -> Response<Body> {
+    if req.method() != Method::GET {
+        return error(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    }
+
+    if req.uri().path() == "/metrics" {
+        return Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(metrics(state)))
+            .unwrap();
+    }
+
+    let route: fn(&MarketState) -> Value = match req.uri().path() {
+        "/ref-price" => ref_price,
+        "/market-data" => market_data,
+        "/positions" => positions,
+        "/fills" => fills,
+        "/decision" => decision,
+        "/health" => health,
+        _ => return error(StatusCode::NOT_FOUND, "not found"),
+    };
+
+    let body: Map<String, Value> = state
+        .markets
+        .iter()
+        .map(|m| (m.market.clone(), route(m)))
+        .collect();
+    let body = Value::Object(body);
+
+    return Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+}
+
+fn error(status: StatusCode, msg: &str) -> Response<Body> {
+    return Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(json!({ "error": msg }).to_string()))
+        .unwrap();
+}
+
+fn ref_price(state: &MarketState) -> Value {
+    let mut value = price(&state.rp.lock().unwrap());
+    // with a single source it is the reference price itself
+    if state.sources.len() > 1 {
+        let sources: Map<String, Value> = state
+            .sources
+            .iter()
+            .map(|s| (s.name.clone(), price(&s.rp.lock().unwrap())))
+            .collect();
+        value["sources"] = Value::Object(sources);
+    }
+    return value;
+}
+
+fn price(rp: &RefPrice) -> Value {
+    let (bid, ask) = rp.get();
+    let (bid_quantity, ask_quantity) = rp.get_quantities();
+    let (bids, asks) = rp.depth();
+    return json!({
+        "bid_price": bid,
+        "ask_price": ask,
+        "bid_quantity": bid_quantity,
+        "ask_quantity": ask_quantity,
+        "mid_price": rp.mid_price(),
+        "bids": bids,
+        "asks": asks,
+        "last_trade": rp.last_trade(),
+        "event_time": rp.event_time(),
+        "age_secs": rp.age().map(|a| a.as_secs_f64()),
+    });
+}
+
+fn market_data(state: &MarketState) -> Value {
+    let md = state.store.lock().unwrap().get_market_data();
+    return json!({
+        "market": md.market,
+        "mark_price": md.mark_price,
+        "best_bid_price": md.best_bid_price,
+        "best_bid_volume": md.best_bid_volume,
+        "best_offer_price": md.best_offer_price,
+        "best_offer_volume": md.best_offer_volume,
+        "mid_price": md.mid_price,
+        "open_interest": md.open_interest,
+        "trading_mode": TradingMode::try_from(md.market_trading_mode)
+            .map(|m| m.as_str_name())
+            .unwrap_or("TRADING_MODE_UNSPECIFIED"),
+        "timestamp": md.timestamp,
+    });
+}
+
+fn positions(state: &MarketState) -> Value {
+    let store = state.store.lock().unwrap();
+    let positions: Vec<Value> = state
+        .pubkeys
+        .iter()
+        .map(|pubkey| match store.get_position(pubkey) {
+            Some(p) => json!({
+                "party_id": p.party_id,
+                "open_volume": p.open_volume,
+                "average_entry_price": p.average_entry_price,
+                "realised_pnl": p.realised_pnl,
+                "unrealised_pnl": p.unrealised_pnl,
+                "updated_at": p.updated_at,
+            }),
+            None => json!({
+                "party_id": pubkey,
+                "open_volume": 0,
+            }),
+        })
+        .collect();
+    return json!(positions);
+}
+
+fn fills(state: &MarketState) -> Value {
+    return json!(state.store.lock().unwrap().get_fills());
+}
+
+fn decision(state: &MarketState) -> Value {
+    return json!(*state.last_decision.lock().unwrap());
+}
+
+fn health(state: &MarketState) -> Value {
+    let ref_price_age = state.rp.lock().unwrap().age().map(|a| a.as_secs_f64());
+    let streams: HashMap<String, Option<u64>> = state
+        .store
+        .lock()
+        .unwrap()
+        .get_streams_health()
+        .into_iter()
+        .map(|(name, since)| (name, since.map(secs_since_epoch)))
+        .collect();
+    let last_cycle_at = state
+        .last_decision
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|d| d.timestamp);
+
+    return json!({
+        "ref_price_age_secs": ref_price_age,
+        "streams_healthy_since": streams,
+        "last_cycle_at": last_cycle_at,
+    });
+}
+
+fn metrics(state: &State) -> String {
+    let mut out = METRICS.render();
+
+    // no sample until the first reference price is received
+    let ref_price_age: Vec<(Vec<(&str, String)>, f64)> = state
+        .markets
+        .iter()
+        .filter_map(|m| {
+            let age = m.rp.lock().unwrap().age()?;
+            Some((vec![("market", m.market.clone())], age.as_secs_f64()))
+        })
+        .collect();
+    write_metric(
+        &mut out,
+        "ref_price_age_seconds",
+        "gauge",
+        "Seconds since the last reference price update",
+        &ref_price_age,
+    );
+
+    let mut open_volumes: Vec<(Vec<(&str, String)>, f64)> = vec![];
+    for m in state.markets.iter() {
+        let store = m.store.lock().unwrap();
+        for pubkey in m.pubkeys.iter() {
+            let open_volume = store.get_position(pubkey).map_or(0, |p| p.open_volume);
+            open_volumes.push((
+                vec![("market", m.market.clone()), ("wallet", pubkey.clone())],
+                open_volume as f64,
+            ));
+        }
+    }
+    write_metric(
+        &mut out,
+        "open_volume",
+        "gauge",
+        "Open volume of the wallets, in market position precision",
+        &open_volumes,
+    );
+
+    return out;
+}
+
+fn secs_since_epoch(t: SystemTime) -> u64 {
+    return t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+}
@@ -0,0 +1,41 @@
+use std::time::Duration;
+
+/// Exponential backoff with full jitter, used to pace reconnection attempts
+/// of the long lived streams (binance websocket, datanode gRPC streams).
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        return Backoff {
+            initial,
+            max,
+            attempt: 0,
+        };
+    }
+
+    /// Return how long to wait before the next attempt, a random duration
+    /// between half and all of the current exponential delay.
+    pub fn next_delay(&mut self) -> Duration {
+        let exp = self
+            .initial
+            .saturating_mul(2_u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+
+        let half = exp.as_millis() as u64 / 2;
+        let jitter = rand::random::<u64>() % (half + 1);
+        return Duration::from_millis(half + jitter);
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn attempt(&self) -> u32 {
+        return self.attempt;
+    }
+}
@@ -1,116 +1,458 @@
-use log::info;
 use serde::{Deserialize, Serialize};
-use std::error::Error as StdError;
-use std::fmt;
+use serde_json::json;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use tungstenite::{connect, Message};
-use url::Url;
+use tokio::sync::watch;
 
-pub struct RefPrice {
-    bid_price: f64,
-    ask_price: f64,
+use crate::reference::{
+    self, parse_level, Level, RefPrice, ReferenceFeed, Update, WsFeed, WsProtocol,
+};
+
+/// Binance stream the reference prices are read from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamKind {
+    /// 24h rolling window statistics, pushed every second
+    Ticker,
+    /// Best bid and ask, pushed in real time
+    #[default]
+    BookTicker,
+    /// Best 5 levels of the book, the mid price is weighted by their quantity
+    Depth5,
+    /// Best 20 levels of the book, the mid price is weighted by their quantity
+    Depth20,
+    /// Aggregated trades, the reference price is the last trade price
+    AggTrade,
 }
 
-impl RefPrice {
-    pub fn new() -> RefPrice {
-        return RefPrice {
-            bid_price: 0.,
-            ask_price: 0.,
+impl StreamKind {
+    fn stream(&self, symbol: &str) -> String {
+        use StreamKind::*;
+        let suffix = match self {
+            Ticker => "ticker",
+            BookTicker => "bookTicker",
+            Depth5 => "depth5@100ms",
+            Depth20 => "depth20@100ms",
+            AggTrade => "aggTrade",
         };
+        return format!("{}@{}", symbol.to_lowercase(), suffix);
     }
+}
 
-    pub fn set(&mut self, bid_price: f64, ask_price: f64) {
-        self.bid_price = bid_price;
-        self.ask_price = ask_price;
-    }
+// the connection is switched to combined mode so every message is
+// wrapped with the name of its stream, the depth streams do not
+// carry their symbol.
+#[derive(Debug, Deserialize)]
+struct StreamMessage {
+    stream: String,
+    data: serde_json::Value,
+}
 
-    pub fn get(&self) -> (f64, f64) {
-        return (self.bid_price, self.ask_price);
-    }
+// ticker and bookTicker, only the ticker reports an event time
+#[derive(Debug, Deserialize)]
+struct TopOfBook {
+    #[serde(rename = "E")]
+    event_time: Option<u64>,
+    b: String,
+    #[serde(rename = "B")]
+    bid_quantity: String,
+    a: String,
+    #[serde(rename = "A")]
+    ask_quantity: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Depth {
+    bids: Vec<(String, String)>,
+    asks: Vec<(String, String)>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AggTrade {
+    #[serde(rename = "E")]
+    event_time: u64,
+    p: String,
+    q: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Request {
-    id: u64,
-    method: String,
-    params: Vec<String>,
+/// Binance websocket API, the symbols are upper case.
+pub struct Binance {
+    pub kind: StreamKind,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Response {
-    pub e: String,
-    pub a: String,
-    pub b: String,
+impl WsProtocol for Binance {
+    fn name(&self) -> &'static str {
+        return "binance";
+    }
+
+    fn subscribe(&self, symbols: &[String]) -> Vec<String> {
+        let streams: Vec<String> = symbols.iter().map(|s| self.kind.stream(s)).collect();
+        return vec![
+            json!({ "id": 1, "method": "SET_PROPERTY", "params": ["combined", true] }).to_string(),
+            json!({ "id": 2, "method": "SUBSCRIBE", "params": streams }).to_string(),
+        ];
+    }
+
+    fn parse(&self, txt: &str) -> Result<Vec<(String, Update)>, String> {
+        // the answers to the requests are not wrapped
+        // with a stream name and are skipped.
+        let msg = match serde_json::from_str::<StreamMessage>(txt) {
+            Ok(msg) => msg,
+            _ => return Ok(vec![]),
+        };
+        let symbol = msg.stream.split('@').next().unwrap_or_default();
+        let update =
+            parse_update(self.kind, msg.data).map_err(|e| format!("{}: {}", msg.stream, e))?;
+        return Ok(vec![(symbol.to_uppercase(), update)]);
+    }
 }
 
-pub async fn start(ws_url: String, mkt: String, rp: Arc<Mutex<RefPrice>>) -> Result<(), Error> {
-    let url = ws_url.parse::<Url>()?;
-    info!("opening websocket with binance API at: {}", url);
-    let (mut socket, _) = connect(url)?;
-    info!("connected to binance successfully");
-
-    let request = serde_json::to_string(&Request {
-        id: 1,
-        method: "SUBSCRIBE".to_string(),
-        params: vec![format!("{}@ticker", mkt.to_lowercase())],
-    })?;
-
-    socket.write_message(Message::Text(request))?;
-
-    // discard first message, it's confirmation from binance
-    socket.read_message()?;
-    loop {
-        let msg = socket.read_message()?;
-        match serde_json::from_str::<Response>(&msg.to_string()) {
-            Ok(r) => {
-                if r.e == "24hrTicker" {
-                    info!("new binance prices: {:?}", r);
-                    rp.lock()
-                        .unwrap()
-                        .set(r.b.parse::<f64>().unwrap(), r.a.parse::<f64>().unwrap());
-                }
+fn parse_update(kind: StreamKind, data: serde_json::Value) -> Result<Update, String> {
+    use StreamKind::*;
+    let update = match kind {
+        Ticker | BookTicker => {
+            let t: TopOfBook = serde_json::from_value(data).map_err(|e| e.to_string())?;
+            Update::TopOfBook {
+                bid: parse_level(&t.b, &t.bid_quantity).ok_or_else(|| format!("bid {:?}", t.b))?,
+                ask: parse_level(&t.a, &t.ask_quantity).ok_or_else(|| format!("ask {:?}", t.a))?,
+                event_time: t.event_time,
             }
-            _ => continue,
         }
-    }
+        Depth5 | Depth20 => {
+            let d: Depth = serde_json::from_value(data).map_err(|e| e.to_string())?;
+            let parse = |levels: &[(String, String)]| -> Option<Vec<Level>> {
+                return levels.iter().map(|(p, q)| parse_level(p, q)).collect();
+            };
+            let bids = parse(&d.bids).ok_or_else(|| format!("bids {:?}", d.bids))?;
+            let asks = parse(&d.asks).ok_or_else(|| format!("asks {:?}", d.asks))?;
+            if bids.is_empty() || asks.is_empty() {
+                return Err("one-sided book".to_string());
+            }
+            Update::Depth { bids, asks }
+        }
+        AggTrade => {
+            let t: AggTrade = serde_json::from_value(data).map_err(|e| e.to_string())?;
+            Update::Trade {
+                trade: parse_level(&t.p, &t.q)
+                    .ok_or_else(|| format!("trade {:?} {:?}", t.p, t.q))?,
+                event_time: t.event_time,
+            }
+        }
+    };
+    return Ok(update);
 }
 
-#[derive(Debug)]
-pub enum Error {
-    WSError,
+/// Stream the prices of all the symbols over a single connection,
+/// each symbol updates its own reference price.
+pub async fn start(
+    ws_url: String,
+    kind: StreamKind,
+    feeds: HashMap<String, Arc<Mutex<RefPrice>>>,
+    shutdown: watch::Receiver<bool>,
+) -> Result<(), reference::Error> {
+    let feed = Box::new(WsFeed::new(Box::new(Binance { kind }), ws_url));
+    return feed.start(feeds, shutdown).await;
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "wallet client error: {}", self.desc())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_ws::{Frame, TestFeed};
+    use crate::test_util::wait_for;
+    use serde_json::Value;
+    use std::time::Duration;
+    use tokio::time;
+
+    // wrap the data as sent on a connection in combined mode
+    fn stream_frame(name: String, data: Value) -> Frame {
+        return Frame::Text(json!({ "stream": name, "data": data }).to_string());
     }
-}
 
-impl From<tungstenite::Error> for Error {
-    fn from(_: tungstenite::Error) -> Self {
-        Error::WSError
+    fn ticker(symbol: &str, bid: &str, ask: &str, event_time: u64) -> Frame {
+        return stream_frame(
+            format!("{}@ticker", symbol.to_lowercase()),
+            json!({
+                "e": "24hrTicker",
+                "E": event_time,
+                "s": symbol,
+                "b": bid,
+                "B": "1.500",
+                "a": ask,
+                "A": "2.000",
+                "c": bid,
+            }),
+        );
     }
-}
 
-impl From<url::ParseError> for Error {
-    fn from(_: url::ParseError) -> Self {
-        Error::WSError
+    fn book_ticker(symbol: &str, bid: (&str, &str), ask: (&str, &str)) -> Frame {
+        return stream_frame(
+            format!("{}@bookTicker", symbol.to_lowercase()),
+            json!({
+                "u": 400900217,
+                "s": symbol,
+                "b": bid.0,
+                "B": bid.1,
+                "a": ask.0,
+                "A": ask.1,
+            }),
+        );
     }
-}
 
-impl From<serde_json::Error> for Error {
-    fn from(_: serde_json::Error) -> Self {
-        Error::WSError
+    fn depth(symbol: &str, levels: usize, bids: &[(&str, &str)], asks: &[(&str, &str)]) -> Frame {
+        return stream_frame(
+            format!("{}@depth{}@100ms", symbol.to_lowercase(), levels),
+            json!({ "lastUpdateId": 160, "bids": bids, "asks": asks }),
+        );
     }
-}
 
-impl StdError for Error {}
+    fn agg_trade(symbol: &str, price: &str, quantity: &str, event_time: u64) -> Frame {
+        return stream_frame(
+            format!("{}@aggTrade", symbol.to_lowercase()),
+            json!({
+                "e": "aggTrade",
+                "E": event_time,
+                "s": symbol,
+                "a": 26129,
+                "p": price,
+                "q": quantity,
+                "T": event_time,
+                "m": true,
+            }),
+        );
+    }
 
-impl Error {
-    pub fn desc(&self) -> String {
-        use Error::*;
-        match self {
-            WSError => format!("websocket error"),
-        }
+    fn subscription_confirmation(id: u64) -> Frame {
+        return Frame::Text(format!(r#"{{"result":null,"id":{}}}"#, id));
+    }
+
+    fn malformed() -> Frame {
+        return Frame::Text(r#"{"stream":"btcusdt@ticker","data":{"#.to_string());
+    }
+
+    async fn start_feed(
+        kind: StreamKind,
+        symbols: &[&str],
+        connections: Vec<Vec<Frame>>,
+    ) -> TestFeed {
+        return TestFeed::start(Box::new(Binance { kind }), symbols, connections).await;
+    }
+
+    #[tokio::test]
+    async fn subscribes_and_updates_the_reference_price() {
+        let feed = start_feed(
+            StreamKind::Ticker,
+            &["BTCUSDT"],
+            vec![vec![
+                subscription_confirmation(1),
+                ticker("BTCUSDT", "30000.10", "30000.20", 1),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTCUSDT").1 == 1).await;
+        assert_eq!(feed.price("BTCUSDT").0, (30000.10, 30000.20));
+        assert!(!feed.feeds["BTCUSDT"]
+            .lock()
+            .unwrap()
+            .is_stale(Duration::from_secs(10)));
+
+        let received = &feed.received()[0];
+        assert_eq!(received[0]["method"], "SET_PROPERTY");
+        assert_eq!(received[0]["params"], json!(["combined", true]));
+        assert_eq!(received[1]["method"], "SUBSCRIBE");
+        assert_eq!(received[1]["params"], json!(["btcusdt@ticker"]));
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn routes_the_tickers_by_symbol() {
+        let feed = start_feed(
+            StreamKind::Ticker,
+            &["BTCUSDT", "ETHUSDT"],
+            vec![vec![
+                ticker("ETHUSDT", "2000.1", "2000.2", 1),
+                ticker("BTCUSDT", "30000.1", "30000.2", 2),
+                // not subscribed, ignored
+                ticker("SOLUSDT", "20.1", "20.2", 3),
+                ticker("ETHUSDT", "2001.1", "2001.2", 4),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("ETHUSDT").1 == 4).await;
+        assert_eq!(feed.price("ETHUSDT").0, (2001.1, 2001.2));
+        assert_eq!(feed.price("BTCUSDT"), ((30000.1, 30000.2), 2));
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn skips_malformed_messages() {
+        let feed = start_feed(
+            StreamKind::Ticker,
+            &["BTCUSDT"],
+            vec![vec![
+                malformed(),
+                // the answer to the subscription is not a stream message
+                Frame::Text(r#"{"result":null,"id":2}"#.to_string()),
+                ticker("BTCUSDT", "30000.1", "30000.2", 2),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTCUSDT").1 == 2).await;
+        assert_eq!(feed.connections(), 1);
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn keeps_the_previous_price_on_invalid_bid_or_ask() {
+        let feed = start_feed(
+            StreamKind::Ticker,
+            &["BTCUSDT"],
+            vec![vec![
+                ticker("BTCUSDT", "30000.1", "30000.2", 1),
+                ticker("BTCUSDT", "not a price", "30000.2", 2),
+                ticker("BTCUSDT", "30000.1", "", 3),
+                ticker("BTCUSDT", "0.00000000", "30000.2", 4),
+                ticker("BTCUSDT", "NaN", "inf", 5),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTCUSDT").1 == 1).await;
+        // give the feed the time to read the invalid tickers
+        time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(feed.price("BTCUSDT"), ((30000.1, 30000.2), 1));
+        assert_eq!(feed.connections(), 1);
+        assert!(!feed.handle.is_finished());
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_resubscribes_after_a_disconnect() {
+        let feed = start_feed(
+            StreamKind::Ticker,
+            &["BTCUSDT"],
+            vec![
+                vec![ticker("BTCUSDT", "30000.1", "30000.2", 1), Frame::Close],
+                vec![
+                    ticker("BTCUSDT", "30001.1", "30001.2", 2),
+                    Frame::Disconnect,
+                ],
+                vec![ticker("BTCUSDT", "30002.1", "30002.2", 3)],
+            ],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTCUSDT").1 == 3).await;
+        assert_eq!(feed.price("BTCUSDT").0, (30002.1, 30002.2));
+        let received = feed.received();
+        assert_eq!(received.len(), 3);
+        assert!(received.iter().all(|r| r.len() == 2));
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn stops_on_shutdown_after_a_disconnect() {
+        let feed = start_feed(
+            StreamKind::Ticker,
+            &["BTCUSDT"],
+            vec![vec![Frame::Disconnect]],
+        )
+        .await;
+
+        wait_for(|| feed.connections() == 1).await;
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn reads_the_best_bid_and_ask_from_the_book_ticker() {
+        let feed = start_feed(
+            StreamKind::BookTicker,
+            &["BTCUSDT"],
+            vec![vec![book_ticker(
+                "BTCUSDT",
+                ("30000.5", "0.5"),
+                ("30001.5", "1.25"),
+            )]],
+        )
+        .await;
+
+        // the book ticker has no event time, the local time is used
+        wait_for(|| feed.price("BTCUSDT").1 > 0).await;
+        let rp = feed.feeds["BTCUSDT"].lock().unwrap();
+        assert_eq!(rp.get(), (30000.5, 30001.5));
+        assert_eq!(rp.get_quantities(), (0.5, 1.25));
+        assert_eq!(rp.mid_price(), 30001.);
+        drop(rp);
+
+        assert_eq!(
+            feed.received()[0][1]["params"],
+            json!(["btcusdt@bookTicker"])
+        );
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn weights_the_mid_price_by_the_depth() {
+        let feed = start_feed(
+            StreamKind::Depth20,
+            &["BTCUSDT"],
+            vec![vec![
+                // one-sided, skipped
+                depth("BTCUSDT", 20, &[("100", "1")], &[]),
+                depth(
+                    "BTCUSDT",
+                    20,
+                    &[("100", "1"), ("99", "3")],
+                    &[("101", "1"), ("102", "3")],
+                ),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTCUSDT").1 > 0).await;
+        let rp = feed.feeds["BTCUSDT"].lock().unwrap();
+        assert_eq!(rp.get(), (100., 101.));
+        assert_eq!(rp.get_quantities(), (1., 1.));
+        // (99.25 + 101.75) / 2
+        assert_eq!(rp.mid_price(), 100.5);
+        assert_eq!(rp.depth().0.len(), 2);
+        assert_eq!(rp.depth().1.len(), 2);
+        drop(rp);
+
+        assert_eq!(
+            feed.received()[0][1]["params"],
+            json!(["btcusdt@depth20@100ms"])
+        );
+        feed.stop().await;
+    }
+
+    #[tokio::test]
+    async fn uses_the_last_trade_price() {
+        let feed = start_feed(
+            StreamKind::AggTrade,
+            &["BTCUSDT"],
+            vec![vec![
+                agg_trade("BTCUSDT", "30000.5", "0.1", 1),
+                agg_trade("BTCUSDT", "-1", "0.1", 2),
+                agg_trade("BTCUSDT", "30001.5", "0.2", 3),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTCUSDT").1 == 3).await;
+        let rp = feed.feeds["BTCUSDT"].lock().unwrap();
+        assert_eq!(rp.get(), (30001.5, 30001.5));
+        assert_eq!(rp.mid_price(), 30001.5);
+        assert_eq!(
+            rp.last_trade(),
+            Some(Level {
+                price: 30001.5,
+                quantity: 0.2
+            })
+        );
+        drop(rp);
+        feed.stop().await;
     }
 }
@@ -0,0 +1,147 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::reference::{parse_level, Update, WsProtocol};
+
+// bybit accepts at most 10 topics per subscription request
+const MAX_TOPICS_PER_REQUEST: usize = 10;
+
+#[derive(Debug, Deserialize)]
+struct OrderBook {
+    #[serde(rename = "type")]
+    typ: String,
+    ts: u64,
+    data: OrderBookData,
+}
+
+#[derive(Debug, Deserialize)]
+struct OrderBookData {
+    s: String,
+    b: Vec<(String, String)>,
+    a: Vec<(String, String)>,
+}
+
+/// Bybit websocket API v5, the symbols are spot
+/// symbols such as BTCUSDT.
+pub struct Bybit;
+
+impl WsProtocol for Bybit {
+    fn name(&self) -> &'static str {
+        return "bybit";
+    }
+
+    // the level 1 order book is the only public spot
+    // stream with the best bid and ask.
+    fn subscribe(&self, symbols: &[String]) -> Vec<String> {
+        let topics: Vec<String> = symbols
+            .iter()
+            .map(|s| format!("orderbook.1.{}", s))
+            .collect();
+        return topics
+            .chunks(MAX_TOPICS_PER_REQUEST)
+            .map(|args| json!({ "op": "subscribe", "args": args }).to_string())
+            .collect();
+    }
+
+    // bybit closes the connections which do not ping
+    fn ping(&self) -> Option<String> {
+        return Some(json!({ "op": "ping" }).to_string());
+    }
+
+    fn parse(&self, txt: &str) -> Result<Vec<(String, Update)>, String> {
+        let msg: Value = match serde_json::from_str(txt) {
+            Ok(msg) => msg,
+            _ => return Ok(vec![]),
+        };
+        // answer to a request or a ping
+        if msg["success"] == false {
+            return Err(msg.to_string());
+        }
+        if !msg["topic"]
+            .as_str()
+            .is_some_and(|t| t.starts_with("orderbook."))
+        {
+            return Ok(vec![]);
+        }
+
+        let book: OrderBook = serde_json::from_value(msg).map_err(|e| e.to_string())?;
+        // the level 1 book is always sent as a snapshot
+        if book.typ != "snapshot" {
+            return Ok(vec![]);
+        }
+        let best = |levels: &[(String, String)]| match levels.first() {
+            Some((p, q)) => parse_level(p, q).ok_or_else(|| format!("level {:?} {:?}", p, q)),
+            None => Err("one-sided book".to_string()),
+        };
+        let bid = best(&book.data.b)?;
+        let ask = best(&book.data.a)?;
+        return Ok(vec![(
+            book.data.s,
+            Update::TopOfBook {
+                bid,
+                ask,
+                event_time: Some(book.ts),
+            },
+        )]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_ws::{Frame, TestFeed};
+    use crate::test_util::wait_for;
+
+    fn order_book(symbol: &str, bid: &[(&str, &str)], ask: &[(&str, &str)], ts: u64) -> Frame {
+        return Frame::json(json!({
+            "topic": format!("orderbook.1.{}", symbol),
+            "type": "snapshot",
+            "ts": ts,
+            "data": { "s": symbol, "b": bid, "a": ask, "u": 18521288, "seq": 7961638724_u64 },
+            "cts": ts,
+        }));
+    }
+
+    #[tokio::test]
+    async fn reads_the_level_1_order_book() {
+        let feed = TestFeed::start(
+            Box::new(Bybit),
+            &["BTCUSDT"],
+            vec![vec![
+                Frame::json(json!({
+                    "success": true,
+                    "ret_msg": "subscribe",
+                    "conn_id": "2324d924-aa4d-45b0-a858-7b8be29ab52b",
+                    "op": "subscribe",
+                })),
+                order_book("BTCUSDT", &[("30000.5", "0.5")], &[("30001.5", "1.5")], 1),
+                order_book("BTCUSDT", &[("30000.5", "0.5")], &[], 2),
+                order_book("BTCUSDT", &[("30000.5", "0.5")], &[("NaN", "1.5")], 3),
+                order_book("BTCUSDT", &[("30002.5", "0.5")], &[("30003.5", "1.5")], 4),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTCUSDT").1 == 4).await;
+        assert_eq!(feed.price("BTCUSDT").0, (30002.5, 30003.5));
+
+        let request = &feed.received()[0][0];
+        assert_eq!(request["op"], "subscribe");
+        assert_eq!(request["args"], json!(["orderbook.1.BTCUSDT"]));
+        feed.stop().await;
+    }
+
+    #[test]
+    fn splits_the_subscriptions() {
+        let symbols: Vec<String> = (0..25).map(|i| format!("SYM{}USDT", i)).collect();
+        assert_eq!(Bybit.subscribe(&symbols).len(), 3);
+    }
+
+    #[test]
+    fn reports_the_failed_requests() {
+        let error = r#"{"success":false,"ret_msg":"error:handler not found","op":"subscribe"}"#;
+        assert!(Bybit.parse(error).is_err());
+        let pong = r#"{"success":true,"ret_msg":"pong","conn_id":"0970e817","op":"ping"}"#;
+        assert_eq!(Bybit.parse(pong), Ok(vec![]));
+    }
+}
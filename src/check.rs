@@ -0,0 +1,435 @@
+use std::collections::{HashMap, HashSet};
+use url::Url;
+use vega_crypto::{Credentials, Transact};
+use vega_protobufs::datanode::api::v2::trading_data_service_client::TradingDataServiceClient;
+use vega_protobufs::vega::instrument::Product;
+
+use crate::strategy2::{settlement_asset, PricingMode};
+use crate::vega_feed::VegaPrice;
+use crate::vega_store2::VegaStore;
+use crate::Config;
+
+// longest submission rate accepted, in seconds
+const MAX_SUBMISSION_RATE: u64 = 24 * 60 * 60;
+
+/// Outcome of the checks run on the configuration.
+pub struct Report {
+    checks: Vec<(String, Result<(), String>)>,
+}
+
+impl Report {
+    fn new() -> Report {
+        return Report { checks: vec![] };
+    }
+
+    fn check(&mut self, what: String, res: Result<(), String>) {
+        self.checks.push((what, res));
+    }
+
+    pub fn is_ok(&self) -> bool {
+        return self.checks.iter().all(|(_, res)| res.is_ok());
+    }
+
+    /// Print every check, or only the failed ones.
+    pub fn print(&self, errors_only: bool) {
+        for (what, res) in self.checks.iter() {
+            match res {
+                Ok(()) if !errors_only => println!("[ok]    {}", what),
+                Ok(()) => {}
+                Err(e) => println!("[error] {}: {}", what, e),
+            }
+        }
+    }
+}
+
+fn ensure(cond: bool, err: String) -> Result<(), String> {
+    if cond {
+        return Ok(());
+    }
+    return Err(err);
+}
+
+fn is_ws_url(url: &str) -> Result<(), String> {
+    return Url::parse(url).map_err(|e| e.to_string()).and_then(|u| {
+        ensure(
+            u.scheme() == "ws" || u.scheme() == "wss",
+            format!("unsupported scheme {}", u.scheme()),
+        )
+    });
+}
+
+/// Check the values of the configuration, without connecting to anything.
+pub fn validate(config: &Config) -> Report {
+    let mut r = Report::new();
+
+    r.check(
+        "vega_grpc_url is a valid url".to_string(),
+        Url::parse(&config.vega_grpc_url)
+            .map(|_| ())
+            .map_err(|e| e.to_string()),
+    );
+    if config.markets.iter().any(|mc| mc.binance_market.is_some()) {
+        r.check(
+            "binance_ws_url is a websocket url".to_string(),
+            match &config.binance_ws_url {
+                Some(url) => is_ws_url(url),
+                None => Err("not set, a market sets binance_market".to_string()),
+            },
+        );
+    }
+    let mut exchanges = HashSet::new();
+    for sc in config.reference_sources.iter() {
+        let name = sc.exchange.name();
+        r.check(
+            format!("reference source {} is configured once", name),
+            ensure(
+                exchanges.insert(sc.exchange),
+                "duplicate source".to_string(),
+            ),
+        );
+        r.check(
+            format!("reference source {} ws_url is a websocket url", name),
+            is_ws_url(&sc.ws_url()),
+        );
+    }
+    if !config.reference_sources.is_empty() {
+        r.check(
+            "max_source_deviation_bps is positive".to_string(),
+            ensure(config.max_source_deviation_bps > 0, "got 0".to_string()),
+        );
+    }
+    r.check(
+        "at least one market is configured".to_string(),
+        ensure(!config.markets.is_empty(), "markets is empty".to_string()),
+    );
+
+    let mut seen = HashSet::new();
+    for mc in config.markets.iter() {
+        let m = &mc.vega_market;
+        r.check(
+            format!("market {} is a valid market ID", m),
+            ensure(
+                m.len() == 64 && m.chars().all(|c| c.is_ascii_hexdigit()),
+                "expected 64 hexadecimal characters".to_string(),
+            ),
+        );
+        r.check(
+            format!("market {} is configured once", m),
+            ensure(seen.insert(m.clone()), "duplicate market".to_string()),
+        );
+        if let Some(symbol) = &mc.binance_market {
+            r.check(
+                format!("market {} binance_market is a symbol", m),
+                ensure(
+                    !symbol.is_empty() && symbol.chars().all(|c| c.is_ascii_alphanumeric()),
+                    format!("invalid symbol {:?}", symbol),
+                ),
+            );
+        }
+        r.check(
+            format!("market {} trade_size is positive", m),
+            ensure(mc.trade_size > 0, format!("got {}", mc.trade_size)),
+        );
+        r.check(
+            format!("market {} submission_rate is sane", m),
+            ensure(
+                mc.submission_rate > 0 && mc.submission_rate <= MAX_SUBMISSION_RATE,
+                format!(
+                    "got {}, expected between 1 and {} seconds",
+                    mc.submission_rate, MAX_SUBMISSION_RATE
+                ),
+            ),
+        );
+        r.check(
+            format!("market {} derives at least one key per wallet", m),
+            ensure(
+                mc.wallets.iter().all(|wc| wc.key_count > 0),
+                "key_count must be positive".to_string(),
+            ),
+        );
+        for (exchange, symbol) in mc.reference_symbols.iter() {
+            r.check(
+                format!(
+                    "market {} {} symbol is a reference source",
+                    m,
+                    exchange.name()
+                ),
+                ensure(
+                    exchanges.contains(exchange) && !symbol.is_empty(),
+                    format!("{} is not in reference_sources", exchange.name()),
+                ),
+            );
+        }
+        r.check(
+            format!("market {} has a reference price source", m),
+            ensure(
+                mc.binance_market.is_some()
+                    || !mc.reference_symbols.is_empty()
+                    || mc.vega_reference.is_some(),
+                "set binance_market, reference_symbols or vega_reference".to_string(),
+            ),
+        );
+        let keys: usize = mc.wallets.iter().map(|wc| wc.key_count).sum();
+        r.check(
+            format!("market {} has at least two wallets", m),
+            ensure(keys >= 2, format!("got {}", keys)),
+        );
+    }
+
+    r.check(
+        "max_ref_price_age is positive".to_string(),
+        ensure(config.max_ref_price_age > 0, "got 0".to_string()),
+    );
+    if let Some(bps) = config.max_ref_price_deviation_bps {
+        r.check(
+            "max_ref_price_deviation_bps is positive".to_string(),
+            ensure(bps > 0, "got 0".to_string()),
+        );
+    }
+    if let PricingMode::Blend {
+        vega_mid,
+        binance_mid,
+        mark_price,
+    } = config.pricing_mode
+    {
+        let weights = [vega_mid, binance_mid, mark_price];
+        r.check(
+            "pricing_mode blend weights are valid".to_string(),
+            ensure(
+                weights.iter().all(|w| *w >= 0.) && weights.iter().sum::<f64>() > 0.,
+                "weights must be positive or zero, and not all zero".to_string(),
+            ),
+        );
+    }
+    if let Some(min) = config.min_general_balance {
+        r.check(
+            "min_general_balance is positive or zero".to_string(),
+            ensure(min >= 0., format!("got {}", min)),
+        );
+    }
+    r.check(
+        "shutdown_timeout is positive".to_string(),
+        ensure(config.shutdown_timeout > 0, "got 0".to_string()),
+    );
+
+    return r;
+}
+
+/// Whether the product of the market is a perpetual.
+pub fn is_perpetual(store: &VegaStore) -> bool {
+    return matches!(
+        store
+            .get_market()
+            .tradable_instrument
+            .and_then(|ti| ti.instrument)
+            .and_then(|i| i.product),
+        Some(Product::Perpetual(_))
+    );
+}
+
+/// Check the configuration against the datanode: the markets exist
+/// and are supported, and the mnemonics derive distinct keys across
+/// all the markets.
+pub async fn check_datanode(config: &Config, r: &mut Report) {
+    let mut clt = match TradingDataServiceClient::connect(config.vega_grpc_url.clone()).await {
+        Ok(clt) => {
+            r.check(format!("connected to {}", config.vega_grpc_url), Ok(()));
+            clt
+        }
+        Err(e) => {
+            r.check(
+                format!("connected to {}", config.vega_grpc_url),
+                Err(e.to_string()),
+            );
+            return;
+        }
+    };
+
+    // by public key, the market it is used on
+    let mut pubkeys: HashMap<String, String> = HashMap::new();
+    for mc in config.markets.iter() {
+        let m = &mc.vega_market;
+        match VegaStore::new(&mut clt, m).await {
+            Ok(store) => {
+                r.check(format!("market {} exists", m), Ok(()));
+                let asset = settlement_asset(&store.get_market());
+                r.check(
+                    format!("market {} product is supported", m),
+                    ensure(
+                        asset.is_some(),
+                        "only futures and perpetuals are supported".to_string(),
+                    ),
+                );
+                if let Some(asset) = asset {
+                    r.check(
+                        format!("market {} settlement asset {} exists", m, asset),
+                        ensure(
+                            store.get_assets().iter().any(|a| a.id == asset),
+                            "asset not found".to_string(),
+                        ),
+                    );
+                }
+                if mc.vega_reference == Some(VegaPrice::OracleTwap) {
+                    r.check(
+                        format!("market {} has an oracle twap", m),
+                        ensure(
+                            is_perpetual(&store),
+                            "oracle_twap is only available on perpetuals".to_string(),
+                        ),
+                    );
+                }
+            }
+            Err(e) => r.check(format!("market {} exists", m), Err(e.desc())),
+        }
+
+        for (i, wc) in mc.wallets.iter().enumerate() {
+            let what = format!("market {} wallet {} derives distinct keys", m, i + 1);
+            let mnemonic = match wc.mnemonic.load() {
+                Ok(mnemonic) => mnemonic,
+                Err(e) => {
+                    r.check(what, Err(e.desc()));
+                    continue;
+                }
+            };
+
+            let mut res = Ok(());
+            for index in wc.derivation_indexes() {
+                let creds = Credentials::Mnemonic(mnemonic.expose(), index);
+                match Transact::new(creds, config.vega_grpc_url.clone()).await {
+                    Ok(w) => {
+                        if let Some(other) = pubkeys.insert(w.public_key(), m.clone()) {
+                            res = Err(format!(
+                                "key {} is already used on market {}",
+                                w.public_key(),
+                                other
+                            ));
+                        }
+                    }
+                    Err(e) => res = Err(e.to_string()),
+                }
+            }
+            r.check(what, res);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reference::Exchange;
+    use serde_json::json;
+
+    const MARKET: &str = "4e9081e20e9e81f3e747d42cb0c9b8826454df01899e6027a22e771e19cc79fc";
+
+    fn config() -> Config {
+        let config = json!({
+            "port": 1789,
+            "vega_grpc_url": "tcp://localhost:3007",
+            "binance_ws_url": "wss://stream.binance.com:443/ws",
+            "reference_sources": [{ "exchange": "coinbase" }],
+            "markets": [{
+                "vega_market": MARKET,
+                "binance_market": "BTCUSDT",
+                "trade_size": 4,
+                "submission_rate": 27,
+                "reference_symbols": { "coinbase": "BTC-USD" },
+                "wallets": [{ "mnemonic": "mnemonic", "key_count": 2 }]
+            }]
+        });
+        return serde_json::from_value(config).unwrap();
+    }
+
+    // names of the failed checks
+    fn failed(config: &Config) -> Vec<String> {
+        return validate(config)
+            .checks
+            .into_iter()
+            .filter(|(_, res)| res.is_err())
+            .map(|(what, _)| what)
+            .collect();
+    }
+
+    #[test]
+    fn accepts_a_valid_configuration() {
+        assert_eq!(failed(&config()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn refuses_a_zero_trade_size_or_submission_rate() {
+        let mut c = config();
+        c.markets[0].trade_size = 0;
+        c.markets[0].submission_rate = 0;
+        assert_eq!(
+            failed(&c),
+            vec![
+                format!("market {} trade_size is positive", MARKET),
+                format!("market {} submission_rate is sane", MARKET),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_a_duplicate_market() {
+        let mut c = config();
+        let dup = serde_json::to_value(&c.markets[0]).unwrap();
+        c.markets.push(serde_json::from_value(dup).unwrap());
+        assert_eq!(
+            failed(&c),
+            vec![format!("market {} is configured once", MARKET)]
+        );
+    }
+
+    #[test]
+    fn refuses_a_wallet_without_keys() {
+        let mut c = config();
+        c.markets[0].wallets[0].key_count = 0;
+        assert_eq!(
+            failed(&c),
+            vec![
+                format!("market {} derives at least one key per wallet", MARKET),
+                format!("market {} has at least two wallets", MARKET),
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_all_zero_blend_weights() {
+        let mut c = config();
+        c.pricing_mode = PricingMode::Blend {
+            vega_mid: 0.,
+            binance_mid: 0.,
+            mark_price: 0.,
+        };
+        assert_eq!(
+            failed(&c),
+            vec!["pricing_mode blend weights are valid".to_string()]
+        );
+    }
+
+    #[test]
+    fn refuses_a_reference_symbol_without_its_source() {
+        let mut c = config();
+        c.markets[0]
+            .reference_symbols
+            .insert(Exchange::Kraken, "XBT/USD".to_string());
+        assert_eq!(
+            failed(&c),
+            vec![format!(
+                "market {} kraken symbol is a reference source",
+                MARKET
+            )]
+        );
+    }
+
+    #[test]
+    fn only_needs_binance_ws_url_for_the_markets_listed_on_binance() {
+        let mut c = config();
+        c.binance_ws_url = None;
+        assert_eq!(
+            failed(&c),
+            vec!["binance_ws_url is a websocket url".to_string()]
+        );
+        c.markets[0].binance_market = None;
+        assert_eq!(failed(&c), Vec::<String>::new());
+    }
+}
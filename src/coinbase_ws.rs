@@ -0,0 +1,121 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::reference::{parse_level, Update, WsProtocol};
+
+#[derive(Debug, Deserialize)]
+struct Ticker {
+    product_id: String,
+    best_bid: String,
+    best_bid_size: String,
+    best_ask: String,
+    best_ask_size: String,
+}
+
+/// Coinbase exchange websocket API, the symbols are
+/// product IDs such as BTC-USD.
+pub struct Coinbase;
+
+impl WsProtocol for Coinbase {
+    fn name(&self) -> &'static str {
+        return "coinbase";
+    }
+
+    // the ticker channel only pushes on trades, the heartbeat
+    // channel keeps the connection busy on quiet products.
+    fn subscribe(&self, symbols: &[String]) -> Vec<String> {
+        return vec![json!({
+            "type": "subscribe",
+            "product_ids": symbols,
+            "channels": ["ticker", "heartbeat"],
+        })
+        .to_string()];
+    }
+
+    fn parse(&self, txt: &str) -> Result<Vec<(String, Update)>, String> {
+        let msg: Value = match serde_json::from_str(txt) {
+            Ok(msg) => msg,
+            _ => return Ok(vec![]),
+        };
+        match msg["type"].as_str() {
+            Some("ticker") => {}
+            Some("error") => return Err(msg.to_string()),
+            _ => return Ok(vec![]),
+        }
+
+        // the time of the ticker is not used, it is an RFC 3339 date
+        let t: Ticker = serde_json::from_value(msg).map_err(|e| e.to_string())?;
+        let bid = parse_level(&t.best_bid, &t.best_bid_size)
+            .ok_or_else(|| format!("bid {:?}", t.best_bid))?;
+        let ask = parse_level(&t.best_ask, &t.best_ask_size)
+            .ok_or_else(|| format!("ask {:?}", t.best_ask))?;
+        return Ok(vec![(
+            t.product_id,
+            Update::TopOfBook {
+                bid,
+                ask,
+                event_time: None,
+            },
+        )]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_ws::{Frame, TestFeed};
+    use crate::test_util::wait_for;
+
+    fn ticker(product_id: &str, bid: &str, ask: &str) -> Frame {
+        return Frame::json(json!({
+            "type": "ticker",
+            "sequence": 37475248783_u64,
+            "product_id": product_id,
+            "price": bid,
+            "best_bid": bid,
+            "best_bid_size": "0.25",
+            "best_ask": ask,
+            "best_ask_size": "0.75",
+            "side": "buy",
+            "time": "2022-10-19T23:28:22.061769Z",
+            "trade_id": 370843401,
+            "last_size": "0.001",
+        }));
+    }
+
+    #[tokio::test]
+    async fn reads_the_ticker_channel() {
+        let feed = TestFeed::start(
+            Box::new(Coinbase),
+            &["BTC-USD"],
+            vec![vec![
+                Frame::json(json!({ "type": "subscriptions", "channels": [] })),
+                Frame::json(json!({ "type": "heartbeat", "product_id": "BTC-USD" })),
+                ticker("ETH-USD", "2000.5", "2001.5"),
+                ticker("BTC-USD", "not a price", "30001.5"),
+                ticker("BTC-USD", "30000.5", "30001.5"),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTC-USD").1 > 0).await;
+        let rp = feed.feeds["BTC-USD"].lock().unwrap();
+        assert_eq!(rp.get(), (30000.5, 30001.5));
+        assert_eq!(rp.get_quantities(), (0.25, 0.75));
+        assert_eq!(rp.mid_price(), 30001.);
+        drop(rp);
+
+        let request = &feed.received()[0][0];
+        assert_eq!(request["type"], "subscribe");
+        assert_eq!(request["product_ids"], json!(["BTC-USD"]));
+        assert_eq!(request["channels"], json!(["ticker", "heartbeat"]));
+        feed.stop().await;
+    }
+
+    #[test]
+    fn reports_the_errors() {
+        let error = r#"{"type":"error","message":"Failed to subscribe","reason":"FOO-BAR is not a valid product"}"#;
+        assert!(Coinbase.parse(error).is_err());
+        assert_eq!(Coinbase.parse("not json"), Ok(vec![]));
+    }
+}
@@ -0,0 +1,533 @@
+use log::info;
+use num_bigint::BigUint;
+use num_traits::cast::{FromPrimitive, ToPrimitive};
+use num_traits::Zero;
+use vega_protobufs::vega::{
+    commands::v1::{BatchMarketInstructions, OrderCancellation, OrderSubmission},
+    order::{TimeInForce, Type},
+    MarketData, Side,
+};
+
+use crate::strategy2::{
+    order_reference, BookFallback, CounterpartySelection, Intent, OutOfBandAction, PricingMode,
+    Snapshot, Strategy, StrategyConfig, WalletBatch,
+};
+
+/// Cross a random size between two wallets of the pool at the price
+/// selected by the pricing mode, alternating sides to keep their
+/// positions small.
+pub struct CrossMid;
+
+impl Strategy for CrossMid {
+    fn decide(&mut self, config: &StrategyConfig, snapshot: &Snapshot) -> Intent {
+        let market = config.market.clone();
+        let (a, b) = match get_counterparties(config.counterparty_selection, snapshot) {
+            Some(pair) => pair,
+            None => {
+                info!("less than two wallets are allowed to trade, skipping");
+                return Intent::Skip("low_balance");
+            }
+        };
+        info!(
+            "selected counterparties: {} and {}",
+            snapshot.wallets[a].name, snapshot.wallets[b].name,
+        );
+
+        let default_trade_size = ((rand::random::<u64>() % config.trade_size as u64) + 1) as i64;
+        info!("selected trade size: {}", default_trade_size,);
+
+        let md = &snapshot.market_data;
+        let md_mid_price = match get_book_state(md) {
+            BookState::TwoSided(md_bid, md_ask) => {
+                let md_mid_price =
+                    (md_ask.clone() + md_bid.clone()) / BigUint::from_i64(2).unwrap();
+                info!(
+                    "new vega reference prices: bestBid({}), bestAsk({}), midPrice({})",
+                    md_bid.to_string(),
+                    md_ask.to_string(),
+                    md_mid_price,
+                );
+                Some(md_mid_price.to_f64().unwrap())
+            }
+            BookState::BidOnly(md_bid) => {
+                info!("vega order book is one-sided: bestBid({}), no ask", md_bid);
+                None
+            }
+            BookState::AskOnly(md_ask) => {
+                info!("vega order book is one-sided: bestAsk({}), no bid", md_ask);
+                None
+            }
+            BookState::Empty => {
+                info!("vega order book is empty");
+                None
+            }
+        };
+
+        let ref_price = snapshot
+            .decimals
+            .to_market_price_precision(snapshot.ref_mid_price());
+        let mark_price = md.mark_price.parse::<f64>().unwrap_or(0.);
+        let vega_mid = match md_mid_price {
+            Some(p) => Some(p),
+            None => match config.one_sided_book_fallback {
+                BookFallback::Skip => None,
+                BookFallback::BinanceMid => {
+                    info!("using binance mid price in place of the vega mid price");
+                    Some(ref_price)
+                }
+                BookFallback::MarkPrice if mark_price > 0. => {
+                    info!("using mark price in place of the vega mid price");
+                    Some(mark_price)
+                }
+                BookFallback::MarkPrice => None,
+            },
+        };
+
+        let source_price =
+            match get_source_price(&config.pricing_mode, vega_mid, ref_price, mark_price) {
+                Some(p) => p,
+                None => {
+                    info!(
+                        "no price available for pricing mode {:?}, skipping",
+                        config.pricing_mode
+                    );
+                    return Intent::Skip("no_price");
+                }
+            };
+        let mut price = BigUint::from_f64(source_price.round()).unwrap();
+
+        if let Some(max_deviation_bps) = config.max_ref_price_deviation_bps {
+            let deviation_bps = price_deviation_bps(source_price, ref_price);
+            info!(
+                "order price deviation from reference: {:.2}bps (max {}bps)",
+                deviation_bps, max_deviation_bps,
+            );
+
+            if deviation_bps > max_deviation_bps as f64 {
+                match config.out_of_band_action {
+                    OutOfBandAction::Skip => {
+                        info!(
+                            "order price {} outside of the reference band around {}, skipping",
+                            price, ref_price,
+                        );
+                        return Intent::Skip("out_of_band");
+                    }
+                    OutOfBandAction::Clamp => {
+                        let clamped = clamp_to_band(source_price, ref_price, max_deviation_bps);
+                        let unclamped = price;
+                        price = BigUint::from_f64(clamped.round()).unwrap();
+                        info!(
+                            "order price {} outside of the reference band around {}, clamped to {}",
+                            unclamped, ref_price, price,
+                        );
+                    }
+                }
+            }
+        }
+
+        info!(
+            "pricing source: {:?}, final order price: {}",
+            config.pricing_mode, price
+        );
+
+        let (a_order_size, b_order_size, is_market) = get_order_sizes(
+            snapshot.wallets[a].open_volume,
+            snapshot.wallets[b].open_volume,
+            default_trade_size,
+        );
+
+        info!("{} order size: {}", snapshot.wallets[a].name, a_order_size);
+        info!("{} order size: {}", snapshot.wallets[b].name, b_order_size);
+        info!("submitting market orders: {}", is_market);
+
+        let batch_a = WalletBatch {
+            wallet: a,
+            size: a_order_size,
+            batch: get_batch(
+                market.clone(),
+                price.to_string(),
+                a_order_size,
+                is_market,
+                order_reference(snapshot.cycle),
+            ),
+        };
+        let batch_b = WalletBatch {
+            wallet: b,
+            size: b_order_size,
+            batch: get_batch(
+                market.clone(),
+                price.to_string(),
+                b_order_size,
+                is_market,
+                order_reference(snapshot.cycle),
+            ),
+        };
+
+        // the buyer goes first
+        let batches = match a_order_size > 0 {
+            true => vec![batch_a, batch_b],
+            false => vec![batch_b, batch_a],
+        };
+
+        return Intent::Trade {
+            price: price.to_string(),
+            batches,
+        };
+    }
+}
+
+// pick the indexes of the two wallets trading this cycle among
+// the ones allowed to trade, None if there is less than two.
+fn get_counterparties(
+    selection: CounterpartySelection,
+    snapshot: &Snapshot,
+) -> Option<(usize, usize)> {
+    let pool: Vec<usize> = (0..snapshot.wallets.len())
+        .filter(|i| !snapshot.wallets[*i].low_balance)
+        .collect();
+    if pool.len() < 2 {
+        return None;
+    }
+
+    let n = pool.len() as u64;
+    let rotation = (
+        pool[(snapshot.cycle % n) as usize],
+        pool[((snapshot.cycle + 1) % n) as usize],
+    );
+
+    match selection {
+        CounterpartySelection::Rotation => return Some(rotation),
+        CounterpartySelection::NetExposure => {
+            let open_volume = |i: &usize| snapshot.wallets[*i].open_volume;
+            let longest = *pool.iter().max_by_key(|i| open_volume(i)).unwrap();
+            let shortest = *pool.iter().min_by_key(|i| open_volume(i)).unwrap();
+            // all the positions are equal, nothing to net
+            if open_volume(&longest) == open_volume(&shortest) {
+                return Some(rotation);
+            }
+            return Some((longest, shortest));
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum BookState {
+    Empty,
+    BidOnly(BigUint),
+    AskOnly(BigUint),
+    TwoSided(BigUint, BigUint),
+}
+
+fn get_book_state(md: &MarketData) -> BookState {
+    // datanode sends an empty string or "0" when a side of the book is empty
+    fn parse_price(price: &str) -> Option<BigUint> {
+        return BigUint::parse_bytes(price.as_bytes(), 10).filter(|p| !p.is_zero());
+    }
+
+    match (
+        parse_price(&md.best_bid_price),
+        parse_price(&md.best_offer_price),
+    ) {
+        (Some(bid), Some(ask)) => BookState::TwoSided(bid, ask),
+        (Some(bid), None) => BookState::BidOnly(bid),
+        (None, Some(ask)) => BookState::AskOnly(ask),
+        (None, None) => BookState::Empty,
+    }
+}
+
+// return the price for the configured pricing mode, in market precision,
+// vega_mid is None if the vega order book is not two-sided.
+fn get_source_price(
+    mode: &PricingMode,
+    vega_mid: Option<f64>,
+    ref_mid: f64,
+    mark_price: f64,
+) -> Option<f64> {
+    let price = match mode {
+        PricingMode::VegaMid => vega_mid?,
+        PricingMode::BinanceMid => ref_mid,
+        PricingMode::MarkPrice => mark_price,
+        PricingMode::Blend {
+            vega_mid: w_vega_mid,
+            binance_mid: w_binance_mid,
+            mark_price: w_mark_price,
+        } => {
+            let total = w_vega_mid + w_binance_mid + w_mark_price;
+            if total <= 0. {
+                return None;
+            }
+            let vega_mid = match vega_mid {
+                Some(p) => p,
+                None if *w_vega_mid == 0. => 0.,
+                None => return None,
+            };
+            (vega_mid * w_vega_mid + ref_mid * w_binance_mid + mark_price * w_mark_price) / total
+        }
+    };
+
+    if price <= 0. {
+        return None;
+    }
+    return Some(price);
+}
+
+// deviation of price from ref_price, in basis points
+fn price_deviation_bps(price: f64, ref_price: f64) -> f64 {
+    return (price - ref_price).abs() / ref_price * 10_000.;
+}
+
+fn clamp_to_band(price: f64, ref_price: f64, max_deviation_bps: u64) -> f64 {
+    let band = ref_price * max_deviation_bps as f64 / 10_000.;
+    return price.clamp(ref_price - band, ref_price + band);
+}
+
+fn get_batch(
+    market_id: String,
+    price: String,
+    mut size: i64,
+    is_market: bool,
+    reference: String,
+) -> BatchMarketInstructions {
+    let mut side = Side::Buy;
+    if size < 0 {
+        side = Side::Sell;
+        size = -size;
+    }
+    let (tif, typ, price) = match is_market {
+        true => (TimeInForce::Ioc, Type::Market, "".to_string()),
+        false => (TimeInForce::Gfn, Type::Limit, price),
+    };
+
+    return BatchMarketInstructions {
+        cancellations: vec![OrderCancellation {
+            order_id: "".to_string(),
+            market_id: market_id.clone(),
+        }],
+        amendments: vec![],
+        submissions: vec![OrderSubmission {
+            expires_at: 0,
+            market_id: market_id.clone(),
+            pegged_order: None,
+            price: price,
+            size: size as u64,
+            reference,
+            side: side.into(),
+            time_in_force: tif.into(),
+            r#type: typ.into(),
+            reduce_only: false,
+            post_only: false,
+            iceberg_opts: None,
+        }],
+        stop_orders_cancellation: vec![],
+        stop_orders_submission: vec![],
+    };
+}
+
+fn get_order_sizes(
+    w1_position_size: i64,
+    w2_position_size: i64,
+    default_trade_size: i64,
+) -> (i64, i64, bool) {
+    match (w1_position_size, w2_position_size) {
+        (0, 0) => return (-default_trade_size, default_trade_size, false),
+        (0, v) => {
+            if v > 0 {
+                return (default_trade_size, -default_trade_size, false);
+            }
+            return (-default_trade_size, default_trade_size, false);
+        }
+        (v, 0) => {
+            if v > 0 {
+                return (-default_trade_size, default_trade_size, false);
+            }
+            return (default_trade_size, -default_trade_size, false);
+        }
+        (v1, v2) if v1 > 0 && v2 > 0 => {
+            return (-default_trade_size, -default_trade_size, true);
+        }
+        (v1, v2) if v1 > 0 && v2 < 0 => return (-default_trade_size, default_trade_size, false),
+        (v1, v2) if v1 < 0 && v2 < 0 => {
+            return (default_trade_size, default_trade_size, true);
+        }
+        (v1, v2) if v1 < 0 && v2 > 0 => return (default_trade_size, -default_trade_size, false),
+        _ => unreachable!("all case should be covered, bad bad bad"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_datanode::{self, market_data};
+    use crate::strategy2::{Decimals, WalletState};
+    use crate::test_util::strategy_config as config;
+    use std::time::Duration;
+
+    fn wallet(i: usize, open_volume: i64, low_balance: bool) -> WalletState {
+        return WalletState {
+            name: format!("wallet {}", i + 1),
+            pubkey: format!("w{}", i + 1),
+            open_volume,
+            general_balance: 0.,
+            margin_balance: 0.,
+            bond_balance: 0.,
+            low_balance,
+        };
+    }
+
+    // the market of the mock datanode, 1 decimal place, with
+    // a reference mid price of 30005
+    fn snapshot(md: MarketData, cycle: u64, wallets: Vec<WalletState>) -> Snapshot {
+        let script = mock_datanode::script();
+        let decimals = Decimals::new(&script.market, &script.assets[0]);
+        return Snapshot {
+            cycle,
+            market: script.market,
+            market_data: md,
+            asset: script.assets[0].clone(),
+            decimals,
+            ref_bid: 30000.,
+            ref_ask: 30010.,
+            ref_mid: 30005.,
+            ref_price_age: Some(Duration::ZERO),
+            wallets,
+        };
+    }
+
+    // price of the orders, None if the cycle is skipped
+    fn decide(config: &StrategyConfig, md: MarketData) -> Option<String> {
+        let snapshot = snapshot(md, 0, vec![wallet(0, 0, false), wallet(1, 0, false)]);
+        return match CrossMid.decide(config, &snapshot) {
+            Intent::Skip(reason) => {
+                assert_eq!(reason, "no_price");
+                None
+            }
+            Intent::Trade { price, .. } => Some(price),
+        };
+    }
+
+    fn with_fallback(fallback: BookFallback) -> StrategyConfig {
+        let mut config = config();
+        config.one_sided_book_fallback = fallback;
+        return config;
+    }
+
+    #[test]
+    fn reads_the_book_state() {
+        let state = |bid: &str, ask: &str| get_book_state(&market_data(bid, ask, "0"));
+        let p = |p: u32| BigUint::from(p);
+
+        assert_eq!(state("", ""), BookState::Empty);
+        assert_eq!(state("0", "0"), BookState::Empty);
+        assert_eq!(state("300000", ""), BookState::BidOnly(p(300000)));
+        assert_eq!(state("300000", "0"), BookState::BidOnly(p(300000)));
+        assert_eq!(state("", "300100"), BookState::AskOnly(p(300100)));
+        assert_eq!(state("0", "300100"), BookState::AskOnly(p(300100)));
+        assert_eq!(
+            state("300000", "300100"),
+            BookState::TwoSided(p(300000), p(300100))
+        );
+    }
+
+    #[test]
+    fn trades_at_the_mid_of_a_two_sided_book() {
+        let md = market_data("300000", "300100", "300500");
+        assert_eq!(decide(&config(), md), Some("300050".to_string()));
+    }
+
+    #[test]
+    fn skips_on_an_empty_or_one_sided_book() {
+        for (bid, ask) in [("", ""), ("0", "0"), ("300000", "0"), ("", "300100")] {
+            assert_eq!(decide(&config(), market_data(bid, ask, "300500")), None);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_binance_mid() {
+        let config = with_fallback(BookFallback::BinanceMid);
+        for (bid, ask) in [("", ""), ("300000", "0"), ("", "300100")] {
+            let md = market_data(bid, ask, "300500");
+            assert_eq!(decide(&config, md), Some("300050".to_string()));
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_mark_price() {
+        let config = with_fallback(BookFallback::MarkPrice);
+        for (bid, ask) in [("", ""), ("300000", "0"), ("", "300100")] {
+            let md = market_data(bid, ask, "300500");
+            assert_eq!(decide(&config, md), Some("300500".to_string()));
+        }
+        // no mark price yet
+        assert_eq!(decide(&config, market_data("", "", "0")), None);
+    }
+
+    #[test]
+    fn prices_without_the_vega_mid() {
+        let price = |mode| get_source_price(&mode, None, 30005., 30050.);
+        let blend = |vega_mid| PricingMode::Blend {
+            vega_mid,
+            binance_mid: 1.,
+            mark_price: 1.,
+        };
+
+        assert_eq!(price(PricingMode::VegaMid), None);
+        assert_eq!(price(PricingMode::BinanceMid), Some(30005.));
+        assert_eq!(price(PricingMode::MarkPrice), Some(30050.));
+        assert_eq!(price(blend(0.)), Some(30027.5));
+        assert_eq!(price(blend(1.)), None);
+        // with the vega mid, for comparison
+        assert_eq!(
+            get_source_price(&blend(2.), Some(30000.), 30005., 30050.),
+            Some(30013.75)
+        );
+    }
+
+    fn counterparties(
+        selection: CounterpartySelection,
+        cycle: u64,
+        wallets: &[(i64, bool)],
+    ) -> Option<(usize, usize)> {
+        let wallets = wallets
+            .iter()
+            .enumerate()
+            .map(|(i, (open_volume, low_balance))| wallet(i, *open_volume, *low_balance))
+            .collect();
+        let snapshot = snapshot(market_data("", "", "0"), cycle, wallets);
+        return get_counterparties(selection, &snapshot);
+    }
+
+    #[test]
+    fn rotates_over_the_wallets_allowed_to_trade() {
+        let rotation = |cycle, wallets: &[(i64, bool)]| {
+            counterparties(CounterpartySelection::Rotation, cycle, wallets)
+        };
+        // the second wallet is below the minimum balance
+        let wallets = [(0, false), (0, true), (0, false), (0, false)];
+
+        assert_eq!(rotation(0, &wallets), Some((0, 2)));
+        assert_eq!(rotation(1, &wallets), Some((2, 3)));
+        assert_eq!(rotation(2, &wallets), Some((3, 0)));
+        assert_eq!(rotation(3, &wallets), Some((0, 2)));
+        assert_eq!(rotation(0, &[(0, false), (0, true), (0, true)]), None);
+    }
+
+    #[test]
+    fn nets_the_longest_and_shortest_positions() {
+        let net = |cycle, wallets: &[(i64, bool)]| {
+            counterparties(CounterpartySelection::NetExposure, cycle, wallets)
+        };
+
+        assert_eq!(
+            net(0, &[(1, false), (5, false), (-3, false), (0, false)]),
+            Some((1, 2))
+        );
+        // the longest wallet is below the minimum balance
+        assert_eq!(
+            net(0, &[(1, false), (5, true), (-3, false), (0, false)]),
+            Some((0, 2))
+        );
+        // nothing to net, falls back to the rotation
+        assert_eq!(net(1, &[(2, false), (2, false), (2, false)]), Some((1, 2)));
+        assert_eq!(net(0, &[(5, false), (-3, true)]), None);
+    }
+}
@@ -0,0 +1,75 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+
+use crate::vega_store2::Fill;
+
+/// Append only journal of the fills of the bot,
+/// one JSON object per line.
+pub struct Journal {
+    path: String,
+    file: File,
+}
+
+impl Journal {
+    pub fn open(path: &str) -> Result<Journal, io::Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        return Ok(Journal {
+            path: path.to_string(),
+            file,
+        });
+    }
+
+    pub fn append(&mut self, fill: &Fill) -> Result<(), io::Error> {
+        let mut line = serde_json::to_string(fill)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        return self.file.flush();
+    }
+
+    pub fn path(&self) -> &str {
+        return &self.path;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn fill(trade_id: &str) -> Fill {
+        return Fill {
+            trade_id: trade_id.to_string(),
+            market_id: "market".to_string(),
+            price: "300050".to_string(),
+            size: 1,
+            aggressor: "SIDE_BUY".to_string(),
+            buyer: "buyer".to_string(),
+            seller: "seller".to_string(),
+            buyer_fee: "10".to_string(),
+            seller_fee: "0".to_string(),
+            timestamp: 1,
+        };
+    }
+
+    #[test]
+    fn appends_one_line_per_fill() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fills.jsonl");
+        let path = path.to_str().unwrap();
+
+        let mut journal = Journal::open(path).unwrap();
+        journal.append(&fill("t1")).unwrap();
+        journal.append(&fill("t2")).unwrap();
+        // reopening keeps the previous fills
+        let mut journal = Journal::open(path).unwrap();
+        journal.append(&fill("t3")).unwrap();
+
+        let data = fs::read_to_string(path).unwrap();
+        let ids: Vec<String> = data
+            .lines()
+            .map(|l| serde_json::from_str::<Fill>(l).unwrap().trade_id)
+            .collect();
+        assert_eq!(ids, vec!["t1", "t2", "t3"]);
+        assert!(data.ends_with('\n'));
+    }
+}
@@ -0,0 +1,164 @@
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::reference::{level, Update, WsProtocol};
+
+#[derive(Debug, Deserialize)]
+struct Ticker {
+    data: Vec<TickerData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TickerData {
+    symbol: String,
+    bid: f64,
+    bid_qty: f64,
+    ask: f64,
+    ask_qty: f64,
+}
+
+/// Kraken websocket API v2, the symbols are pairs such as BTC/USD.
+pub struct Kraken;
+
+impl WsProtocol for Kraken {
+    fn name(&self) -> &'static str {
+        return "kraken";
+    }
+
+    fn subscribe(&self, symbols: &[String]) -> Vec<String> {
+        return vec![json!({
+            "method": "subscribe",
+            "params": { "channel": "ticker", "symbol": symbols },
+        })
+        .to_string()];
+    }
+
+    fn parse(&self, txt: &str) -> Result<Vec<(String, Update)>, String> {
+        let msg: Value = match serde_json::from_str(txt) {
+            Ok(msg) => msg,
+            _ => return Ok(vec![]),
+        };
+        // answer to a request
+        if msg["success"] == false {
+            return Err(msg.to_string());
+        }
+        // the heartbeat and status channels are skipped
+        if msg["channel"] != "ticker" {
+            return Ok(vec![]);
+        }
+
+        let t: Ticker = serde_json::from_value(msg).map_err(|e| e.to_string())?;
+        let mut updates = vec![];
+        for d in t.data.into_iter() {
+            // an invalid entry does not discard the other symbols of the message
+            match (level(d.bid, d.bid_qty), level(d.ask, d.ask_qty)) {
+                (Some(bid), Some(ask)) => updates.push((
+                    d.symbol,
+                    Update::TopOfBook {
+                        bid,
+                        ask,
+                        event_time: None,
+                    },
+                )),
+                _ => warn!(
+                    "invalid kraken ticker for {}, skipping: bid {}, ask {}",
+                    d.symbol, d.bid, d.ask
+                ),
+            }
+        }
+        return Ok(updates);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_ws::{Frame, TestFeed};
+    use crate::test_util::wait_for;
+
+    fn ticker(typ: &str, symbol: &str, bid: f64, ask: f64) -> Frame {
+        return Frame::json(json!({
+            "channel": "ticker",
+            "type": typ,
+            "data": [{
+                "symbol": symbol,
+                "bid": bid,
+                "bid_qty": 0.5,
+                "ask": ask,
+                "ask_qty": 1.5,
+                "last": bid,
+                "volume": 1234.5,
+                "vwap": bid,
+                "low": bid,
+                "high": ask,
+                "change": 0.0,
+                "change_pct": 0.0,
+            }],
+        }));
+    }
+
+    #[tokio::test]
+    async fn reads_the_ticker_channel() {
+        let feed = TestFeed::start(
+            Box::new(Kraken),
+            &["BTC/USD", "ETH/USD"],
+            vec![vec![
+                Frame::json(json!({ "channel": "status", "type": "update", "data": [] })),
+                Frame::json(json!({
+                    "method": "subscribe",
+                    "result": { "channel": "ticker", "symbol": "BTC/USD" },
+                    "success": true,
+                })),
+                Frame::json(json!({ "channel": "heartbeat" })),
+                ticker("snapshot", "ETH/USD", 2000.5, 2001.5),
+                ticker("update", "BTC/USD", -1., 30001.5),
+                ticker("update", "BTC/USD", 30000.5, 30001.5),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTC/USD").1 > 0).await;
+        assert_eq!(feed.price("BTC/USD").0, (30000.5, 30001.5));
+        assert_eq!(feed.price("ETH/USD").0, (2000.5, 2001.5));
+        let rp = feed.feeds["BTC/USD"].lock().unwrap();
+        assert_eq!(rp.get_quantities(), (0.5, 1.5));
+        drop(rp);
+
+        let request = &feed.received()[0][0];
+        assert_eq!(request["method"], "subscribe");
+        assert_eq!(request["params"]["channel"], "ticker");
+        let mut symbols: Vec<String> =
+            serde_json::from_value(request["params"]["symbol"].clone()).unwrap();
+        symbols.sort();
+        assert_eq!(symbols, vec!["BTC/USD", "ETH/USD"]);
+        feed.stop().await;
+    }
+
+    #[test]
+    fn keeps_the_valid_entries_of_a_message() {
+        let entry = |symbol: &str, bid: f64| {
+            json!({
+                "symbol": symbol,
+                "bid": bid,
+                "bid_qty": 0.5,
+                "ask": 2001.5,
+                "ask_qty": 1.5,
+            })
+        };
+        let msg = json!({
+            "channel": "ticker",
+            "type": "snapshot",
+            "data": [entry("BTC/USD", -1.), entry("ETH/USD", 2000.5)],
+        });
+        let updates = Kraken.parse(&msg.to_string()).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].0, "ETH/USD");
+    }
+
+    #[test]
+    fn reports_the_failed_requests() {
+        let error = r#"{"error":"Currency pair not supported FOO/BAR","method":"subscribe","success":false}"#;
+        assert!(Kraken.parse(error).is_err());
+    }
+}
@@ -1,20 +1,45 @@
-use clap::Parser;
-use log::info;
+use clap::{Parser, Subcommand};
+use futures::future::join_all;
+use log::{error, info, warn};
+use reference::{RefPrice, ReferenceFeed};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
 use tokio::time;
 use vega_crypto::Credentials;
 use vega_crypto::Transact;
 use vega_protobufs::datanode::api::v2::trading_data_service_client::TradingDataServiceClient;
 use vega_store2::update_forever;
 
-// mod api;
+mod api;
+mod backoff;
 mod binance_ws;
+mod bybit_ws;
+mod check;
+mod coinbase_ws;
+mod cross_mid;
+mod journal;
+mod kraken_ws;
+mod metrics;
+#[cfg(test)]
+mod mock_datanode;
+#[cfg(test)]
+mod mock_ws;
+mod okx_ws;
+mod reference;
+mod secret;
 //mod strategy;
 mod strategy2;
+#[cfg(test)]
+mod test_util;
+mod vega_feed;
 mod vega_store2;
+mod wallet;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -22,19 +47,218 @@ struct Cli {
     /// Path to the configuration
     #[arg(long, default_value_t = String::from("config.json"))]
     config: String,
+    /// Log the commands instead of sending them, and simulate the positions
+    #[arg(long)]
+    dry_run: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the configuration against the datanode, print a report and exit
+    CheckConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Config {
     port: u16,
+    /// Address the status API listens on
+    #[serde(default = "default_bind_address")]
+    bind_address: IpAddr,
     vega_grpc_url: String,
-    binance_ws_url: String,
-    wallet_mnemonic_1: String,
-    wallet_mnemonic_2: String,
+    /// Only needed when a market sets `binance_market`
+    binance_ws_url: Option<String>,
+    /// Binance stream the reference prices are read from
+    #[serde(default)]
+    binance_stream: binance_ws::StreamKind,
+    /// Other exchanges the reference prices are read from, besides binance
+    #[serde(default)]
+    reference_sources: Vec<reference::SourceConfig>,
+    /// How the prices of binance and the other sources are combined
+    #[serde(default)]
+    reference_aggregation: reference::Aggregation,
+    /// Maximum deviation in basis points of a source from the median of all the sources
+    #[serde(default = "default_max_source_deviation_bps")]
+    max_source_deviation_bps: u64,
+    /// Markets traded by the bot, each one runs its own strategy
+    #[serde(default)]
+    markets: Vec<MarketConfig>,
+    /// Single market configuration from before `markets`, read as its only entry
+    #[serde(flatten)]
+    legacy: LegacyMarketConfig,
+    /// Strategy deciding the orders sent each cycle
+    #[serde(default)]
+    strategy: strategy2::StrategyKind,
+    /// Maximum age in seconds of the reference price before trading pauses
+    #[serde(default = "default_max_ref_price_age")]
+    max_ref_price_age: u64,
+    /// Maximum deviation in basis points between the vega and reference prices
+    max_ref_price_deviation_bps: Option<u64>,
+    /// Action taken when the vega price is outside of the deviation band
+    #[serde(default)]
+    out_of_band_action: strategy2::OutOfBandAction,
+    /// Source of the price used for the cross trade
+    #[serde(default)]
+    pricing_mode: strategy2::PricingMode,
+    /// Price used when the vega order book is empty or one-sided
+    #[serde(default)]
+    one_sided_book_fallback: strategy2::BookFallback,
+    /// How the two wallets trading each cycle are picked
+    #[serde(default)]
+    counterparty_selection: strategy2::CounterpartySelection,
+    /// Minimum general account balance each wallet must hold to trade
+    min_general_balance: Option<f64>,
+    /// Path of the file the fills of the bot are appended to
+    trade_journal_path: Option<String>,
+    /// Close the positions of the wallets with market orders on shutdown
+    #[serde(default)]
+    flatten_on_shutdown: bool,
+    /// Maximum time in seconds to wait for orders and positions to be closed on shutdown
+    #[serde(default = "default_shutdown_timeout")]
+    shutdown_timeout: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct MarketConfig {
     vega_market: String,
-    binance_market: String,
+    /// Symbol of the market on binance, if listed
+    #[serde(default)]
+    binance_market: Option<String>,
     trade_size: i64,
     submission_rate: u64,
+    /// Symbol of the market on each of the other reference sources
+    #[serde(default)]
+    reference_symbols: HashMap<reference::Exchange, String>,
+    /// Price of the vega market data used as a reference source
+    #[serde(default)]
+    vega_reference: Option<vega_feed::VegaPrice>,
+    /// Wallets trading against each other, at least two
+    wallets: Vec<WalletConfig>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LegacyMarketConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vega_market: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    binance_market: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trade_size: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submission_rate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wallet_mnemonic_1: Option<secret::SecretSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wallet_mnemonic_2: Option<secret::SecretSource>,
+}
+
+const LEGACY_FIELDS: &str =
+    "vega_market, binance_market, trade_size, submission_rate, wallet_mnemonic_1 and wallet_mnemonic_2";
+
+impl Config {
+    // move the single market configuration to `markets`, the two
+    // mnemonics become two wallets of one key at index 1
+    fn migrate_legacy(&mut self) -> Result<(), String> {
+        let l = std::mem::take(&mut self.legacy);
+        if l.vega_market.is_none()
+            && l.binance_market.is_none()
+            && l.trade_size.is_none()
+            && l.submission_rate.is_none()
+            && l.wallet_mnemonic_1.is_none()
+            && l.wallet_mnemonic_2.is_none()
+        {
+            return Ok(());
+        }
+        if !self.markets.is_empty() {
+            return Err(format!(
+                "{} can not be used along with markets, move them to an entry of markets",
+                LEGACY_FIELDS
+            ));
+        }
+        let market = match (
+            l.vega_market,
+            l.binance_market,
+            l.trade_size,
+            l.submission_rate,
+            l.wallet_mnemonic_1,
+            l.wallet_mnemonic_2,
+        ) {
+            (Some(vm), Some(bm), Some(size), Some(rate), Some(m1), Some(m2)) => MarketConfig {
+                vega_market: vm,
+                binance_market: Some(bm),
+                trade_size: size,
+                submission_rate: rate,
+                reference_symbols: HashMap::new(),
+                vega_reference: None,
+                wallets: vec![m1, m2]
+                    .into_iter()
+                    .map(|mnemonic| WalletConfig {
+                        mnemonic,
+                        derivation_index: default_derivation_index(),
+                        key_count: default_key_count(),
+                    })
+                    .collect(),
+            },
+            _ => {
+                return Err(format!(
+                    "the single market configuration needs all of {}, or use markets instead",
+                    LEGACY_FIELDS
+                ))
+            }
+        };
+        warn!(
+            "{} are deprecated, move them to an entry of markets with \
+             the mnemonics as two wallets",
+            LEGACY_FIELDS
+        );
+        self.markets.push(market);
+        return Ok(());
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WalletConfig {
+    mnemonic: secret::SecretSource,
+    /// Index of the first key derived from the mnemonic
+    #[serde(default = "default_derivation_index")]
+    derivation_index: usize,
+    /// Number of keys derived from the mnemonic, at consecutive indexes
+    #[serde(default = "default_key_count")]
+    key_count: usize,
+}
+
+impl WalletConfig {
+    fn derivation_indexes(&self) -> std::ops::Range<usize> {
+        return self.derivation_index..self.derivation_index + self.key_count;
+    }
+}
+
+fn default_derivation_index() -> usize {
+    return 1;
+}
+
+fn default_key_count() -> usize {
+    return 1;
+}
+
+fn default_bind_address() -> IpAddr {
+    return IpAddr::V4(Ipv4Addr::LOCALHOST);
+}
+
+fn default_shutdown_timeout() -> u64 {
+    return 30;
+}
+
+// extra time given to the strategy to return after its own shutdown timeout
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(10);
+
+fn default_max_ref_price_age() -> u64 {
+    return 10;
+}
+
+fn default_max_source_deviation_bps() -> u64 {
+    return 100;
 }
 
 #[tokio::main]
@@ -42,67 +266,362 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     pretty_env_logger::init();
     let cli = Cli::parse();
 
-    let data = fs::read_to_string(&*cli.config).expect("unable to read configuration file");
-    let config: Config = serde_json::from_str(&data).expect("unable to parse configuration file");
+    let data = fs::read_to_string(&*cli.config)
+        .map_err(|e| format!("unable to read configuration file {}: {}", cli.config, e))?;
+    let mut config: Config = serde_json::from_str(&data)
+        .map_err(|e| format!("unable to parse configuration file {}: {}", cli.config, e))?;
+    config
+        .migrate_legacy()
+        .map_err(|e| format!("invalid configuration file {}: {}", cli.config, e))?;
 
-    let w1 = Transact::new(
-        Credentials::Mnemonic(&config.wallet_mnemonic_1, 1),
-        config.vega_grpc_url.clone(),
-    )
-    .await?;
-    info!("loaded wallet 1 with address {}", w1.public_key());
+    let mut report = check::validate(&config);
+    if let Some(Command::CheckConfig) = cli.command {
+        if report.is_ok() {
+            check::check_datanode(&config, &mut report).await;
+        }
+        report.print(false);
+        if !report.is_ok() {
+            return Err("invalid configuration".into());
+        }
+        println!("configuration is valid");
+        return Ok(());
+    }
 
-    let w2 = Transact::new(
-        Credentials::Mnemonic(&config.wallet_mnemonic_2, 1),
-        config.vega_grpc_url.clone(),
-    )
-    .await?;
-    info!("loaded wallet 2 with address {}", w2.public_key());
+    if !report.is_ok() {
+        report.print(true);
+        return Err("invalid configuration".into());
+    }
 
     // info!("connection with the go wallet service successful");
 
-    let rp = Arc::new(Mutex::new(binance_ws::RefPrice::new()));
+    // one reference price per binance symbol, all streamed over a single connection
+    let mut rps = HashMap::new();
+    for mc in config.markets.iter() {
+        if let Some(symbol) = &mc.binance_market {
+            rps.entry(symbol.to_uppercase())
+                .or_insert_with(|| Arc::new(Mutex::new(RefPrice::new())));
+        }
+    }
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
-    tokio::spawn(binance_ws::start(
-        config.binance_ws_url.clone(),
-        config.binance_market.clone(),
-        rp.clone(),
-    ));
+    // no connection to binance when none of the markets is listed there,
+    // binance_ws_url is set otherwise, see check::validate
+    if let (false, Some(url)) = (rps.is_empty(), &config.binance_ws_url) {
+        tokio::spawn(binance_ws::start(
+            url.clone(),
+            config.binance_stream,
+            rps.clone(),
+            shutdown_rx.clone(),
+        ));
+    }
+
+    // same for each of the other sources, over one connection per exchange
+    let mut source_rps = HashMap::new();
+    for sc in config.reference_sources.iter() {
+        let mut rps = HashMap::new();
+        for symbol in config
+            .markets
+            .iter()
+            .filter_map(|mc| mc.reference_symbols.get(&sc.exchange))
+        {
+            rps.entry(symbol.clone())
+                .or_insert_with(|| Arc::new(Mutex::new(RefPrice::new())));
+        }
+        if rps.is_empty() {
+            warn!(
+                "no market sets a {} symbol, not connecting to it",
+                sc.exchange.name()
+            );
+        } else {
+            tokio::spawn(sc.feed().start(rps.clone(), shutdown_rx.clone()));
+        }
+        source_rps.insert(sc.exchange, rps);
+    }
 
     let addr = config.vega_grpc_url.clone();
     let mut tdclt = TradingDataServiceClient::connect(addr).await?;
 
-    let vstore = Arc::new(Mutex::new(
-        vega_store2::VegaStore::new(&mut tdclt, &*config.vega_market).await?,
-    ));
-
-    update_forever(
-        vstore.clone(),
-        tdclt,
-        &*config.vega_market,
-        &*w1.public_key().clone(),
-        &*w2.public_key().clone(),
-    );
-
-    tokio::spawn(strategy2::start(
-        w1.clone(),
-        w2.clone(),
-        config.trade_size,
-        config.vega_market.clone(),
-        vstore.clone(),
-        rp.clone(),
-        config.submission_rate,
-    ));
-
-    // just loop forever, waiting for user interupt
-    let mut interval = time::interval(Duration::from_secs(1));
-    loop {
-        tokio::select! {
-            _ = interval.tick() => {
-                interval.reset();
+    let journal = match &config.trade_journal_path {
+        Some(path) => {
+            info!("writing fills to trade journal at: {}", path);
+            Some(Arc::new(Mutex::new(journal::Journal::open(path)?)))
+        }
+        None => None,
+    };
+
+    if cli.dry_run {
+        info!("running in dry run mode, no transaction will be sent");
+    }
+
+    // bound before trading, the bot does not run without its status API
+    let api_addr = SocketAddr::new(config.bind_address, config.port);
+    let server = api::bind(api_addr)
+        .map_err(|e| format!("unable to start the status API on {}: {}", api_addr, e))?;
+
+    // all the markets and wallets are loaded before trading, the same key in
+    // two markets would have the strategies trade it against itself
+    let mut loaded = vec![];
+    let mut seen: HashMap<String, String> = HashMap::new();
+    for mc in config.markets.iter() {
+        info!("loading market {}", mc.vega_market);
+        let store = vega_store2::VegaStore::new(&mut tdclt, &*mc.vega_market).await?;
+        // the strategies need the settlement asset of the market
+        if strategy2::settlement_asset(&store.get_market()).is_none() {
+            return Err(format!(
+                "market {} is not supported, only futures and perpetuals are",
+                mc.vega_market
+            )
+            .into());
+        }
+        if mc.vega_reference == Some(vega_feed::VegaPrice::OracleTwap)
+            && !check::is_perpetual(&store)
+        {
+            return Err(format!(
+                "market {} has no oracle twap, only perpetuals do",
+                mc.vega_market
+            )
+            .into());
+        }
+
+        let mut wallets: Vec<Transact> = vec![];
+        for wc in mc.wallets.iter() {
+            let mnemonic = wc.mnemonic.load()?;
+            for index in wc.derivation_indexes() {
+                let w = Transact::new(
+                    Credentials::Mnemonic(mnemonic.expose(), index),
+                    config.vega_grpc_url.clone(),
+                )
+                .await?;
+                info!(
+                    "loaded wallet {} with address {} (derivation index {})",
+                    wallets.len() + 1,
+                    w.public_key(),
+                    index,
+                );
+                if let Some(other) = seen.insert(w.public_key(), mc.vega_market.clone()) {
+                    return Err(match other == mc.vega_market {
+                        true => format!(
+                            "wallet {} is configured more than once for {}",
+                            w.public_key(),
+                            mc.vega_market,
+                        ),
+                        false => format!(
+                            "wallet {} is configured for both {} and {}",
+                            w.public_key(),
+                            other,
+                            mc.vega_market,
+                        ),
+                    }
+                    .into());
+                }
+                wallets.push(w);
+            }
+        }
+        loaded.push((store, wallets));
+    }
+
+    let mut markets = vec![];
+    let mut strategies = vec![];
+    for (mc, (store, wallets)) in config.markets.iter().zip(loaded) {
+        info!("starting market {}", mc.vega_market);
+        let pubkeys: Vec<String> = wallets.iter().map(|w| w.public_key()).collect();
+
+        let vstore = Arc::new(Mutex::new(store));
+
+        update_forever(
+            vstore.clone(),
+            tdclt.clone(),
+            &*mc.vega_market,
+            &pubkeys,
+            journal.clone(),
+        );
+
+        let mut sources = vec![];
+        if let Some(symbol) = &mc.binance_market {
+            sources.push(reference::Source {
+                name: "binance".to_string(),
+                rp: rps[&symbol.to_uppercase()].clone(),
+            });
+        }
+        for sc in config.reference_sources.iter() {
+            if let Some(symbol) = mc.reference_symbols.get(&sc.exchange) {
+                sources.push(reference::Source {
+                    name: sc.exchange.name().to_string(),
+                    rp: source_rps[&sc.exchange][symbol].clone(),
+                });
             }
         }
+        if let Some(price) = mc.vega_reference {
+            let rp = Arc::new(Mutex::new(RefPrice::new()));
+            let feed = Box::new(vega_feed::VegaFeed::new(vstore.clone(), price));
+            sources.push(reference::Source {
+                name: feed.name().to_string(),
+                rp: rp.clone(),
+            });
+            let feeds = HashMap::from([(mc.vega_market.clone(), rp)]);
+            tokio::spawn(feed.start(feeds, shutdown_rx.clone()));
+        }
+
+        // a single source is used directly
+        let rp = match sources.len() {
+            1 => sources[0].rp.clone(),
+            _ => {
+                let rp = Arc::new(Mutex::new(RefPrice::new()));
+                tokio::spawn(reference::aggregate_forever(
+                    sources.clone(),
+                    rp.clone(),
+                    reference::AggregatorConfig {
+                        aggregation: config.reference_aggregation,
+                        max_age: Duration::from_secs(config.max_ref_price_age),
+                        max_deviation_bps: config.max_source_deviation_bps,
+                    },
+                    shutdown_rx.clone(),
+                ));
+                rp
+            }
+        };
+        let last_decision = Arc::new(Mutex::new(None));
+
+        strategies.push(tokio::spawn(strategy2::start(
+            wallets
+                .into_iter()
+                .map(|w| wallet::Wallet::new(w, cli.dry_run))
+                .collect(),
+            strategy2::StrategyConfig {
+                strategy: config.strategy,
+                market: mc.vega_market.clone(),
+                trade_size: mc.trade_size,
+                submission_rate: mc.submission_rate,
+                max_ref_price_age: Duration::from_secs(config.max_ref_price_age),
+                max_ref_price_deviation_bps: config.max_ref_price_deviation_bps,
+                out_of_band_action: config.out_of_band_action,
+                pricing_mode: config.pricing_mode,
+                one_sided_book_fallback: config.one_sided_book_fallback,
+                counterparty_selection: config.counterparty_selection,
+                min_general_balance: config.min_general_balance,
+                flatten_on_shutdown: config.flatten_on_shutdown,
+                shutdown_timeout: Duration::from_secs(config.shutdown_timeout),
+            },
+            vstore.clone(),
+            rp.clone(),
+            last_decision.clone(),
+            shutdown_rx.clone(),
+        )));
+
+        markets.push(api::MarketState {
+            market: mc.vega_market.clone(),
+            store: vstore,
+            rp,
+            sources,
+            last_decision,
+            pubkeys,
+        });
+    }
+
+    tokio::spawn(async move {
+        if let Err(e) = api::start(server, markets).await {
+            error!("status API stopped: {}", e);
+        }
+    });
+
+    // run until interrupted, then let the strategies clean up
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT"),
+        _ = sigterm.recv() => info!("received SIGTERM"),
+    }
+    let _ = shutdown_tx.send(true);
+
+    let timeout = Duration::from_secs(config.shutdown_timeout) + SHUTDOWN_GRACE;
+    match time::timeout(timeout, join_all(strategies)).await {
+        Ok(_) => info!("shutdown complete"),
+        Err(_) => info!(
+            "strategies did not stop after {:?}, exiting anyway",
+            timeout
+        ),
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(extra: &str) -> Config {
+        let data = format!(
+            r#"{{
+                "port": 1789,
+                "vega_grpc_url": "tcp://localhost:3007",
+                "binance_ws_url": "wss://stream.binance.com:443/ws",
+                {}
+            }}"#,
+            extra
+        );
+        return serde_json::from_str(&data).unwrap();
+    }
+
+    const LEGACY: &str = r#"
+        "vega_market": "market",
+        "binance_market": "BTCUSDT",
+        "trade_size": 4,
+        "submission_rate": 27,
+        "wallet_mnemonic_1": "mnemonic 1",
+        "wallet_mnemonic_2": "mnemonic 2"
+    "#;
+
+    #[test]
+    fn reads_the_single_market_configuration_as_markets() {
+        let mut config = parse(LEGACY);
+        config.migrate_legacy().unwrap();
+        assert_eq!(config.markets.len(), 1);
+        let market = &config.markets[0];
+        assert_eq!(market.vega_market, "market");
+        assert_eq!(market.binance_market.as_deref(), Some("BTCUSDT"));
+        assert_eq!(market.trade_size, 4);
+        assert_eq!(market.submission_rate, 27);
+        assert_eq!(market.wallets.len(), 2);
+        for (w, mnemonic) in market.wallets.iter().zip(["mnemonic 1", "mnemonic 2"]) {
+            assert_eq!(w.mnemonic.load().unwrap().expose(), mnemonic);
+            assert_eq!(w.derivation_indexes(), 1..2);
+        }
     }
 
-    // tokio::spawn(api::start(cli.port, vstore.clone(), rp.clone()));
+    #[test]
+    fn refuses_an_incomplete_or_mixed_single_market_configuration() {
+        let mut config = parse(r#""vega_market": "market""#);
+        assert!(config.migrate_legacy().is_err());
+
+        let mut config = parse(&format!(r#"{}, "markets": []"#, LEGACY));
+        assert!(config.migrate_legacy().is_ok());
+
+        let markets = r#""markets": [{
+            "vega_market": "other", "trade_size": 1, "submission_rate": 1, "wallets": []
+        }]"#;
+        let mut config = parse(&format!("{}, {}", LEGACY, markets));
+        assert!(config.migrate_legacy().is_err());
+    }
+
+    #[test]
+    fn redacts_the_mnemonics_of_the_configuration() {
+        let config = parse(
+            r#""markets": [{
+                "vega_market": "market", "trade_size": 1, "submission_rate": 1,
+                "wallets": [{ "mnemonic": "tiger harbor velvet" }]
+            }]"#,
+        );
+        assert!(!format!("{:?}", config).contains("tiger harbor velvet"));
+
+        let mut legacy = parse(LEGACY);
+        assert!(!format!("{:?}", legacy).contains("mnemonic 1"));
+        legacy.migrate_legacy().unwrap();
+        assert!(!format!("{:?}", legacy).contains("mnemonic 1"));
+    }
+
+    #[test]
+    fn leaves_the_markets_configuration_as_it_is() {
+        let mut config = parse(r#""markets": []"#);
+        config.migrate_legacy().unwrap();
+        assert!(config.markets.is_empty());
+    }
 }
@@ -0,0 +1,263 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+const PREFIX: &str = "nice_prices_bot";
+
+/// Process wide counters, exposed in the prometheus text format.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    // key = market
+    cycles_run: Mutex<BTreeMap<String, u64>>,
+    // key = (market, skip reason)
+    cycles_skipped: Mutex<BTreeMap<(String, String), u64>>,
+    // key = wallet public key
+    orders_sent: Mutex<BTreeMap<String, u64>>,
+    // key = wallet public key, orders logged in dry run mode
+    orders_simulated: Mutex<BTreeMap<String, u64>>,
+    // key = wallet public key
+    transaction_errors: Mutex<BTreeMap<String, u64>>,
+    // key = reference feed name
+    reference_messages: Mutex<BTreeMap<String, u64>>,
+    // key = stream name, which includes the market
+    stream_reconnects: Mutex<BTreeMap<String, u64>>,
+    // key = market, in the settlement asset units of the market
+    traded_notional: Mutex<BTreeMap<String, f64>>,
+}
+
+impl Metrics {
+    const fn new() -> Metrics {
+        return Metrics {
+            cycles_run: Mutex::new(BTreeMap::new()),
+            cycles_skipped: Mutex::new(BTreeMap::new()),
+            orders_sent: Mutex::new(BTreeMap::new()),
+            orders_simulated: Mutex::new(BTreeMap::new()),
+            transaction_errors: Mutex::new(BTreeMap::new()),
+            reference_messages: Mutex::new(BTreeMap::new()),
+            stream_reconnects: Mutex::new(BTreeMap::new()),
+            traded_notional: Mutex::new(BTreeMap::new()),
+        };
+    }
+
+    pub fn inc_cycles_run(&self, market: &str) {
+        inc(&self.cycles_run, market, 1);
+    }
+
+    pub fn inc_reference_messages(&self, feed: &str) {
+        inc(&self.reference_messages, feed, 1);
+    }
+
+    pub fn inc_cycles_skipped(&self, market: &str, reason: &str) {
+        let key = (market.to_string(), reason.to_string());
+        *self.cycles_skipped.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+
+    pub fn add_orders_sent(&self, wallet: &str, orders: u64) {
+        inc(&self.orders_sent, wallet, orders);
+    }
+
+    pub fn add_orders_simulated(&self, wallet: &str, orders: u64) {
+        inc(&self.orders_simulated, wallet, orders);
+    }
+
+    pub fn inc_transaction_errors(&self, wallet: &str) {
+        inc(&self.transaction_errors, wallet, 1);
+    }
+
+    pub fn inc_stream_reconnects(&self, stream: &str) {
+        inc(&self.stream_reconnects, stream, 1);
+    }
+
+    pub fn add_traded_notional(&self, market: &str, notional: f64) {
+        *self
+            .traded_notional
+            .lock()
+            .unwrap()
+            .entry(market.to_string())
+            .or_insert(0.) += notional;
+    }
+
+    /// Render all the counters in the prometheus text format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        write_labeled(
+            &mut out,
+            "cycles_run_total",
+            "counter",
+            "Number of strategy cycles run, by market",
+            "market",
+            &self.cycles_run,
+        );
+        let skipped: Vec<(Vec<(&str, String)>, f64)> = self
+            .cycles_skipped
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((market, reason), v)| {
+                let labels = vec![("market", market.clone()), ("reason", reason.clone())];
+                return (labels, *v as f64);
+            })
+            .collect();
+        write_metric(
+            &mut out,
+            "cycles_skipped_total",
+            "counter",
+            "Number of strategy cycles skipped, by market and reason",
+            &skipped,
+        );
+        write_labeled(
+            &mut out,
+            "orders_sent_total",
+            "counter",
+            "Number of orders sent, by wallet",
+            "wallet",
+            &self.orders_sent,
+        );
+        write_labeled(
+            &mut out,
+            "orders_simulated_total",
+            "counter",
+            "Number of orders logged instead of sent in dry run mode, by wallet",
+            "wallet",
+            &self.orders_simulated,
+        );
+        write_labeled(
+            &mut out,
+            "transaction_errors_total",
+            "counter",
+            "Number of transactions which failed to be sent, by wallet",
+            "wallet",
+            &self.transaction_errors,
+        );
+        write_labeled(
+            &mut out,
+            "reference_messages_total",
+            "counter",
+            "Number of messages received from the reference feeds, by feed",
+            "feed",
+            &self.reference_messages,
+        );
+        // kept for the dashboards using it, reference_messages_total
+        // has the same count with the binance feed label
+        let binance_messages = self
+            .reference_messages
+            .lock()
+            .unwrap()
+            .get("binance")
+            .copied();
+        write_metric(
+            &mut out,
+            "binance_messages_total",
+            "counter",
+            "Number of messages received from binance, deprecated",
+            &[(vec![], binance_messages.unwrap_or(0) as f64)],
+        );
+        write_labeled(
+            &mut out,
+            "stream_reconnects_total",
+            "counter",
+            "Number of datanode stream reconnections, by stream",
+            "stream",
+            &self.stream_reconnects,
+        );
+        let notional: Vec<(Vec<(&str, String)>, f64)> = self
+            .traded_notional
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(market, v)| (vec![("market", market.clone())], *v))
+            .collect();
+        write_metric(
+            &mut out,
+            "traded_notional_total",
+            "counter",
+            "Notional traded by the wallets, by market, in the settlement asset of the market",
+            &notional,
+        );
+        return out;
+    }
+}
+
+fn inc(counters: &Mutex<BTreeMap<String, u64>>, key: &str, by: u64) {
+    *counters.lock().unwrap().entry(key.to_string()).or_insert(0) += by;
+}
+
+fn write_labeled(
+    out: &mut String,
+    name: &str,
+    typ: &str,
+    help: &str,
+    label: &str,
+    counters: &Mutex<BTreeMap<String, u64>>,
+) {
+    let values: Vec<(Vec<(&str, String)>, f64)> = counters
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (vec![(label, k.clone())], *v as f64))
+        .collect();
+    write_metric(out, name, typ, help, &values);
+}
+
+/// Write a metric and its samples, with their labels,
+/// in the prometheus text format.
+pub fn write_metric(
+    out: &mut String,
+    name: &str,
+    typ: &str,
+    help: &str,
+    values: &[(Vec<(&str, String)>, f64)],
+) {
+    let _ = writeln!(out, "# HELP {}_{} {}", PREFIX, name, help);
+    let _ = writeln!(out, "# TYPE {}_{} {}", PREFIX, name, typ);
+    for (labels, value) in values.iter() {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{}_{} {}", PREFIX, name, value);
+            continue;
+        }
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+            .collect();
+        let _ = writeln!(out, "{}_{}{{{}}} {}", PREFIX, name, labels.join(","), value);
+    }
+}
+
+// escape a label value as required by the text format
+fn escape(value: &str) -> String {
+    return value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_the_label_values() {
+        let mut out = String::new();
+        let labels = vec![("reason", "a \"b\"\\c\nd".to_string())];
+        write_metric(&mut out, "test", "counter", "Test", &[(labels, 1.)]);
+        assert!(out.ends_with("nice_prices_bot_test{reason=\"a \\\"b\\\"\\\\c\\nd\"} 1\n"));
+    }
+
+    #[test]
+    fn labels_the_strategy_metrics_by_market() {
+        let metrics = Metrics::new();
+        metrics.inc_cycles_run("m1");
+        metrics.inc_cycles_run("m2");
+        metrics.inc_cycles_skipped("m1", "no reference price");
+        metrics.add_traded_notional("m2", 1.5);
+        metrics.add_traded_notional("m2", 1.);
+        let out = metrics.render();
+        assert!(out.contains("nice_prices_bot_cycles_run_total{market=\"m1\"} 1\n"));
+        assert!(out.contains("nice_prices_bot_cycles_run_total{market=\"m2\"} 1\n"));
+        assert!(out.contains(
+            "nice_prices_bot_cycles_skipped_total{market=\"m1\",reason=\"no reference price\"} 1\n"
+        ));
+        assert!(out.contains("nice_prices_bot_traded_notional_total{market=\"m2\"} 2.5\n"));
+    }
+}
@@ -0,0 +1,455 @@
+// In-process stand-in for the datanode gRPC API, serving a scripted
+// market so the store and the strategy can run under cargo test.
+// Only the routes used by the bot are implemented, the other ones
+// answer with the UNIMPLEMENTED status.
+
+use futures::stream::{self, Stream, StreamExt};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tonic::codec::ProstCodec;
+use tonic::codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError};
+use tonic::server::{Grpc, NamedService, ServerStreamingService, UnaryService};
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+use vega_protobufs::datanode::api::v2::{
+    observe_accounts_response, observe_orders_response, observe_positions_response,
+    trading_data_service_client::TradingDataServiceClient, AccountBalance, AccountSnapshotPage,
+    AssetEdge, AssetsConnection, GetLatestMarketDataRequest, GetLatestMarketDataResponse,
+    GetMarketRequest, GetMarketResponse, ListAssetsRequest, ListAssetsResponse,
+    ObserveAccountsRequest, ObserveAccountsResponse, ObserveMarketsDataRequest,
+    ObserveMarketsDataResponse, ObserveOrdersRequest, ObserveOrdersResponse,
+    ObservePositionsRequest, ObservePositionsResponse, ObserveTradesRequest, ObserveTradesResponse,
+    OrderSnapshotPage, OrderUpdates, PositionSnapshotPage, PositionUpdates,
+};
+use vega_protobufs::vega::{
+    instrument::Product, order, AccountType, Asset, AssetDetails, Instrument, Market, MarketData,
+    Order, Perpetual, Position, TradableInstrument, Trade,
+};
+
+use crate::vega_store2::VegaStore;
+
+const SERVICE: &str = "datanode.api.v2.TradingDataService";
+
+type ResponseStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + 'static>>;
+
+/// Messages sent on one subscription to a stream, which is then
+/// closed by the server or left open.
+pub struct Subscription<T> {
+    pub messages: Vec<Result<T, Status>>,
+    pub close: bool,
+}
+
+/// State served by the mock datanode.
+#[derive(Default)]
+pub struct Script {
+    pub market: Market,
+    // returned by GetLatestMarketData
+    pub market_data: MarketData,
+    pub assets: Vec<Asset>,
+    // one entry per successive subscription, once all are used
+    // the next subscriptions stay open without any message
+    pub market_data_streams: VecDeque<Subscription<ObserveMarketsDataResponse>>,
+    // by party ID
+    pub positions_streams: HashMap<String, VecDeque<Subscription<ObservePositionsResponse>>>,
+    // once all are used, the next subscriptions only send an
+    // empty snapshot, so the orders and accounts are known
+    pub orders_streams: VecDeque<Subscription<ObserveOrdersResponse>>,
+    // by party ID, same as the orders once all are used
+    pub accounts_streams: HashMap<String, VecDeque<Subscription<ObserveAccountsResponse>>>,
+    pub trades_streams: VecDeque<Subscription<ObserveTradesResponse>>,
+    // number of calls, by method name
+    pub calls: HashMap<String, usize>,
+}
+
+#[derive(Clone)]
+pub struct MockDatanode {
+    script: Arc<Mutex<Script>>,
+}
+
+impl MockDatanode {
+    /// Serve the script on a random local port.
+    pub async fn start(script: Script) -> (Arc<Mutex<Script>>, SocketAddr) {
+        let script = Arc::new(Mutex::new(script));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = stream::unfold(listener, |listener| async move {
+            let conn = listener.accept().await.map(|(socket, _)| socket);
+            Some((conn, listener))
+        });
+
+        let svc = MockDatanode {
+            script: script.clone(),
+        };
+        tokio::spawn(
+            Server::builder()
+                .add_service(svc)
+                .serve_with_incoming(incoming),
+        );
+        return (script, addr);
+    }
+}
+
+pub async fn connect(addr: SocketAddr) -> TradingDataServiceClient<Channel> {
+    return TradingDataServiceClient::connect(format!("http://{}", addr))
+        .await
+        .unwrap();
+}
+
+/// Serve the script and load the store of its market, the client
+/// can be used to start the streams of the store.
+pub async fn store(
+    script: Script,
+) -> (
+    Arc<Mutex<Script>>,
+    Arc<Mutex<VegaStore>>,
+    TradingDataServiceClient<Channel>,
+) {
+    let (script, addr) = MockDatanode::start(script).await;
+    let mut clt = connect(addr).await;
+    let store = VegaStore::new(&mut clt, MARKET_ID).await.unwrap();
+    return (script, Arc::new(Mutex::new(store)), clt);
+}
+
+impl NamedService for MockDatanode {
+    const NAME: &'static str = SERVICE;
+}
+
+impl<B> Service<http::Request<B>> for MockDatanode
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        return Poll::Ready(Ok(()));
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let path = req.uri().path().to_string();
+        let method = path.trim_start_matches(&format!("/{}/", SERVICE));
+        let script = self.script.clone();
+        *script
+            .lock()
+            .unwrap()
+            .calls
+            .entry(method.to_string())
+            .or_insert(0) += 1;
+
+        match method {
+            "GetMarket" => {
+                return unary(req, move |_: GetMarketRequest| GetMarketResponse {
+                    market: Some(script.lock().unwrap().market.clone()),
+                })
+            }
+            "GetLatestMarketData" => {
+                return unary(req, move |_: GetLatestMarketDataRequest| {
+                    GetLatestMarketDataResponse {
+                        market_data: Some(script.lock().unwrap().market_data.clone()),
+                    }
+                })
+            }
+            "ListAssets" => {
+                return unary(req, move |_: ListAssetsRequest| {
+                    let edges = script
+                        .lock()
+                        .unwrap()
+                        .assets
+                        .iter()
+                        .map(|a| AssetEdge {
+                            node: Some(a.clone()),
+                            cursor: a.id.clone(),
+                        })
+                        .collect();
+                    ListAssetsResponse {
+                        assets: Some(AssetsConnection {
+                            edges,
+                            ..Default::default()
+                        }),
+                    }
+                })
+            }
+            "ObserveMarketsData" => {
+                return streaming(req, move |_: ObserveMarketsDataRequest| {
+                    script.lock().unwrap().market_data_streams.pop_front()
+                })
+            }
+            "ObservePositions" => {
+                return streaming(req, move |r: ObservePositionsRequest| {
+                    let party = r.party_id.unwrap_or_default();
+                    script
+                        .lock()
+                        .unwrap()
+                        .positions_streams
+                        .get_mut(&party)
+                        .and_then(|s| s.pop_front())
+                })
+            }
+            "ObserveOrders" => {
+                return streaming(req, move |_: ObserveOrdersRequest| {
+                    let sub = script.lock().unwrap().orders_streams.pop_front();
+                    Some(sub.unwrap_or_else(|| open_with(orders_snapshot(vec![]))))
+                })
+            }
+            "ObserveAccounts" => {
+                return streaming(req, move |r: ObserveAccountsRequest| {
+                    let sub = script
+                        .lock()
+                        .unwrap()
+                        .accounts_streams
+                        .get_mut(&r.party_id)
+                        .and_then(|s| s.pop_front());
+                    Some(sub.unwrap_or_else(|| open_with(accounts_snapshot(vec![]))))
+                })
+            }
+            "ObserveTrades" => {
+                return streaming(req, move |_: ObserveTradesRequest| {
+                    script.lock().unwrap().trades_streams.pop_front()
+                })
+            }
+            _ => {
+                return Box::pin(async move {
+                    Ok(http::Response::builder()
+                        .status(200)
+                        .header("grpc-status", "12")
+                        .header("content-type", "application/grpc")
+                        .body(empty_body())
+                        .unwrap())
+                })
+            }
+        }
+    }
+}
+
+fn open_with<T>(message: T) -> Subscription<T> {
+    return Subscription {
+        messages: vec![Ok(message)],
+        close: false,
+    };
+}
+
+struct Unary<F>(F);
+
+impl<Req, Resp, F> UnaryService<Req> for Unary<F>
+where
+    F: FnMut(Req) -> Resp,
+{
+    type Response = Resp;
+    type Future = future::Ready<Result<Response<Resp>, Status>>;
+
+    fn call(&mut self, req: Request<Req>) -> Self::Future {
+        return future::ready(Ok(Response::new((self.0)(req.into_inner()))));
+    }
+}
+
+struct Streaming<F>(F);
+
+impl<Req, Resp, F> ServerStreamingService<Req> for Streaming<F>
+where
+    F: FnMut(Req) -> Option<Subscription<Resp>>,
+    Resp: Send + 'static,
+{
+    type Response = Resp;
+    type ResponseStream = ResponseStream<Resp>;
+    type Future = future::Ready<Result<Response<Self::ResponseStream>, Status>>;
+
+    fn call(&mut self, req: Request<Req>) -> Self::Future {
+        let sub = (self.0)(req.into_inner()).unwrap_or(Subscription {
+            messages: vec![],
+            close: false,
+        });
+        let messages = stream::iter(sub.messages);
+        let stream: Self::ResponseStream = match sub.close {
+            true => Box::pin(messages),
+            false => Box::pin(messages.chain(stream::pending())),
+        };
+        return future::ready(Ok(Response::new(stream)));
+    }
+}
+
+fn unary<B, Req, Resp, F>(
+    req: http::Request<B>,
+    f: F,
+) -> BoxFuture<http::Response<tonic::body::BoxBody>, Infallible>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnMut(Req) -> Resp + Send + 'static,
+{
+    return Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::<Resp, Req>::default());
+        return Ok(grpc.unary(Unary(f), req).await);
+    });
+}
+
+fn streaming<B, Req, Resp, F>(
+    req: http::Request<B>,
+    f: F,
+) -> BoxFuture<http::Response<tonic::body::BoxBody>, Infallible>
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+    Req: prost::Message + Default + Send + 'static,
+    Resp: prost::Message + Send + 'static,
+    F: FnMut(Req) -> Option<Subscription<Resp>> + Send + 'static,
+{
+    return Box::pin(async move {
+        let mut grpc = Grpc::new(ProstCodec::<Resp, Req>::default());
+        return Ok(grpc.server_streaming(Streaming(f), req).await);
+    });
+}
+
+pub const MARKET_ID: &str = "4e9081e20e9e81f3e747d42cb0c9b8826454df01899e6027a22e771e19cc79fc";
+pub const ASSET_ID: &str = "b340c130096819428a62e5df407fd6abe66e444b89ad64f670beb98621c9c663";
+
+/// A perpetual market with 1 decimal place for the prices,
+/// settled in an asset with 6 decimals.
+pub fn script() -> Script {
+    return Script {
+        market: Market {
+            id: MARKET_ID.to_string(),
+            decimal_places: 1,
+            position_decimal_places: 0,
+            tradable_instrument: Some(TradableInstrument {
+                instrument: Some(Instrument {
+                    name: "BTC/USD-PERP".to_string(),
+                    product: Some(Product::Perpetual(Perpetual {
+                        settlement_asset: ASSET_ID.to_string(),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        market_data: market_data("300000", "300100", "300050"),
+        assets: vec![Asset {
+            id: ASSET_ID.to_string(),
+            details: Some(AssetDetails {
+                name: "USDT".to_string(),
+                decimals: 6,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+}
+
+pub fn market_data(best_bid: &str, best_offer: &str, mark_price: &str) -> MarketData {
+    return MarketData {
+        market: MARKET_ID.to_string(),
+        best_bid_price: best_bid.to_string(),
+        best_offer_price: best_offer.to_string(),
+        mark_price: mark_price.to_string(),
+        ..Default::default()
+    };
+}
+
+pub fn position(party_id: &str, open_volume: i64) -> Position {
+    return Position {
+        market_id: MARKET_ID.to_string(),
+        party_id: party_id.to_string(),
+        open_volume,
+        ..Default::default()
+    };
+}
+
+pub fn positions_snapshot(positions: Vec<Position>) -> ObservePositionsResponse {
+    return ObservePositionsResponse {
+        response: Some(observe_positions_response::Response::Snapshot(
+            PositionSnapshotPage {
+                positions,
+                last_page: true,
+            },
+        )),
+    };
+}
+
+pub fn positions_update(positions: Vec<Position>) -> ObservePositionsResponse {
+    return ObservePositionsResponse {
+        response: Some(observe_positions_response::Response::Updates(
+            PositionUpdates { positions },
+        )),
+    };
+}
+
+pub fn market_data_update(md: MarketData) -> ObserveMarketsDataResponse {
+    return ObserveMarketsDataResponse {
+        market_data: vec![md],
+    };
+}
+
+pub fn order(
+    id: &str,
+    party_id: &str,
+    reference: &str,
+    status: order::Status,
+    created_at: i64,
+) -> Order {
+    return Order {
+        id: id.to_string(),
+        market_id: MARKET_ID.to_string(),
+        party_id: party_id.to_string(),
+        reference: reference.to_string(),
+        status: status as i32,
+        created_at,
+        ..Default::default()
+    };
+}
+
+pub fn orders_snapshot(orders: Vec<Order>) -> ObserveOrdersResponse {
+    return ObserveOrdersResponse {
+        response: Some(observe_orders_response::Response::Snapshot(
+            OrderSnapshotPage {
+                orders,
+                last_page: true,
+            },
+        )),
+    };
+}
+
+pub fn orders_update(orders: Vec<Order>) -> ObserveOrdersResponse {
+    return ObserveOrdersResponse {
+        response: Some(observe_orders_response::Response::Updates(OrderUpdates {
+            orders,
+        })),
+    };
+}
+
+/// An account of the party in the settlement asset, the
+/// balance is in asset precision.
+pub fn account(owner: &str, account_type: AccountType, balance: &str) -> AccountBalance {
+    return AccountBalance {
+        owner: owner.to_string(),
+        balance: balance.to_string(),
+        asset: ASSET_ID.to_string(),
+        r#type: account_type as i32,
+        ..Default::default()
+    };
+}
+
+pub fn accounts_snapshot(accounts: Vec<AccountBalance>) -> ObserveAccountsResponse {
+    return ObserveAccountsResponse {
+        response: Some(observe_accounts_response::Response::Snapshot(
+            AccountSnapshotPage {
+                accounts,
+                last_page: true,
+            },
+        )),
+    };
+}
+
+pub fn trades(trades: Vec<Trade>) -> ObserveTradesResponse {
+    return ObserveTradesResponse { trades };
+}
@@ -0,0 +1,172 @@
+// Local stand-in for the websocket APIs of the exchanges, replaying
+// scripted frames so the reference feeds can run under cargo test.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+use crate::reference::{self, RefPrice, ReferenceFeed, WsFeed, WsProtocol};
+use crate::test_util::with_timeout;
+
+/// A frame sent by the server.
+pub enum Frame {
+    Text(String),
+    // close the connection with a close frame
+    Close,
+    // drop the connection without any close frame
+    Disconnect,
+}
+
+impl Frame {
+    pub fn json(value: Value) -> Frame {
+        return Frame::Text(value.to_string());
+    }
+}
+
+/// State served by the mock websocket API.
+#[derive(Default)]
+pub struct Script {
+    // frames sent on each successive connection, once the subscription
+    // request is received. Once all are used the next connections stay
+    // open without any message.
+    pub connections: VecDeque<Vec<Frame>>,
+    // text messages received on each connection
+    pub received: Vec<Vec<String>>,
+}
+
+pub struct MockWs;
+
+impl MockWs {
+    /// Serve the script on a random local port, return the websocket url.
+    pub async fn start(script: Script) -> (Arc<Mutex<Script>>, String) {
+        let script = Arc::new(Mutex::new(script));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+
+        let s = script.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(serve(socket, s.clone()));
+            }
+        });
+        return (script, url);
+    }
+}
+
+async fn serve(socket: TcpStream, script: Arc<Mutex<Script>>) {
+    let mut ws = match accept_async(socket).await {
+        Ok(ws) => ws,
+        Err(_) => return,
+    };
+    let (conn, frames) = {
+        let mut script = script.lock().unwrap();
+        script.received.push(vec![]);
+        (
+            script.received.len() - 1,
+            script.connections.pop_front().unwrap_or_default(),
+        )
+    };
+
+    let mut subscribed = false;
+    let mut frames = frames.into_iter();
+    loop {
+        // the frames are replayed after the subscription request
+        if subscribed {
+            match frames.next() {
+                Some(Frame::Text(txt)) => {
+                    if ws.send(Message::Text(txt)).await.is_err() {
+                        return;
+                    }
+                    continue;
+                }
+                Some(Frame::Close) => {
+                    let _ = ws.close(None).await;
+                    return;
+                }
+                Some(Frame::Disconnect) => return,
+                None => {}
+            }
+        }
+
+        match ws.next().await {
+            Some(Ok(Message::Text(txt))) => {
+                subscribed |= txt.to_lowercase().contains("subscribe");
+                script.lock().unwrap().received[conn].push(txt);
+            }
+            Some(Ok(_)) => {}
+            _ => return,
+        }
+    }
+}
+
+/// A websocket feed connected to a mock websocket API.
+pub struct TestFeed {
+    pub script: Arc<Mutex<Script>>,
+    pub feeds: HashMap<String, Arc<Mutex<RefPrice>>>,
+    pub handle: JoinHandle<Result<(), reference::Error>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl TestFeed {
+    pub async fn start(
+        protocol: Box<dyn WsProtocol>,
+        symbols: &[&str],
+        connections: Vec<Vec<Frame>>,
+    ) -> TestFeed {
+        let (script, url) = MockWs::start(Script {
+            connections: connections.into(),
+            ..Default::default()
+        })
+        .await;
+        let feeds: HashMap<String, Arc<Mutex<RefPrice>>> = symbols
+            .iter()
+            .map(|s| (s.to_string(), Arc::new(Mutex::new(RefPrice::new()))))
+            .collect();
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let feed = Box::new(WsFeed::new(protocol, url));
+        let handle = tokio::spawn(feed.start(feeds.clone(), shutdown_rx));
+        return TestFeed {
+            script,
+            feeds,
+            handle,
+            shutdown,
+        };
+    }
+
+    /// Bid and ask prices, and event time of the symbol.
+    pub fn price(&self, symbol: &str) -> ((f64, f64), u64) {
+        let rp = self.feeds[symbol].lock().unwrap();
+        return (rp.get(), rp.event_time());
+    }
+
+    pub fn connections(&self) -> usize {
+        return self.script.lock().unwrap().received.len();
+    }
+
+    /// Messages received on each connection, parsed as json.
+    pub fn received(&self) -> Vec<Vec<Value>> {
+        return self
+            .script
+            .lock()
+            .unwrap()
+            .received
+            .iter()
+            .map(|msgs| {
+                msgs.iter()
+                    .map(|m| serde_json::from_str(m).unwrap_or(Value::Null))
+                    .collect()
+            })
+            .collect();
+    }
+
+    pub async fn stop(self) {
+        self.shutdown.send(true).unwrap();
+        let res = with_timeout(self.handle).await.unwrap();
+        assert!(res.is_ok());
+    }
+}
@@ -0,0 +1,165 @@
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::reference::{parse_level, Update, WsProtocol};
+
+#[derive(Debug, Deserialize)]
+struct Tickers {
+    data: Vec<TickerData>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TickerData {
+    inst_id: String,
+    bid_px: String,
+    bid_sz: String,
+    ask_px: String,
+    ask_sz: String,
+    // milliseconds since epoch
+    ts: String,
+}
+
+/// OKX websocket API v5, the symbols are instrument
+/// IDs such as BTC-USDT.
+pub struct Okx;
+
+impl WsProtocol for Okx {
+    fn name(&self) -> &'static str {
+        return "okx";
+    }
+
+    fn subscribe(&self, symbols: &[String]) -> Vec<String> {
+        let args: Vec<Value> = symbols
+            .iter()
+            .map(|s| json!({ "channel": "tickers", "instId": s }))
+            .collect();
+        return vec![json!({ "op": "subscribe", "args": args }).to_string()];
+    }
+
+    // okx closes the connections without any message for 30 seconds,
+    // the pong is not json and is skipped.
+    fn ping(&self) -> Option<String> {
+        return Some("ping".to_string());
+    }
+
+    fn parse(&self, txt: &str) -> Result<Vec<(String, Update)>, String> {
+        let msg: Value = match serde_json::from_str(txt) {
+            Ok(msg) => msg,
+            _ => return Ok(vec![]),
+        };
+        // answer to a request
+        if msg["event"] == "error" {
+            return Err(msg.to_string());
+        }
+        if msg["arg"]["channel"] != "tickers" || msg.get("data").is_none() {
+            return Ok(vec![]);
+        }
+
+        let t: Tickers = serde_json::from_value(msg).map_err(|e| e.to_string())?;
+        let mut updates = vec![];
+        for d in t.data.into_iter() {
+            // an invalid entry does not discard the other symbols of the message
+            match (
+                parse_level(&d.bid_px, &d.bid_sz),
+                parse_level(&d.ask_px, &d.ask_sz),
+            ) {
+                (Some(bid), Some(ask)) => updates.push((
+                    d.inst_id,
+                    Update::TopOfBook {
+                        bid,
+                        ask,
+                        event_time: d.ts.parse::<u64>().ok(),
+                    },
+                )),
+                _ => warn!(
+                    "invalid okx ticker for {}, skipping: bid {:?}, ask {:?}",
+                    d.inst_id, d.bid_px, d.ask_px
+                ),
+            }
+        }
+        return Ok(updates);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_ws::{Frame, TestFeed};
+    use crate::test_util::wait_for;
+
+    fn ticker(inst_id: &str, bid: &str, ask: &str, ts: u64) -> Frame {
+        return Frame::json(json!({
+            "arg": { "channel": "tickers", "instId": inst_id },
+            "data": [{
+                "instType": "SPOT",
+                "instId": inst_id,
+                "last": bid,
+                "lastSz": "0.1",
+                "askPx": ask,
+                "askSz": "1.5",
+                "bidPx": bid,
+                "bidSz": "0.5",
+                "open24h": bid,
+                "high24h": ask,
+                "low24h": bid,
+                "ts": ts.to_string(),
+            }],
+        }));
+    }
+
+    #[tokio::test]
+    async fn reads_the_tickers_channel() {
+        let feed = TestFeed::start(
+            Box::new(Okx),
+            &["BTC-USDT"],
+            vec![vec![
+                Frame::json(json!({
+                    "event": "subscribe",
+                    "arg": { "channel": "tickers", "instId": "BTC-USDT" },
+                    "connId": "a4d3ae55",
+                })),
+                Frame::Text("pong".to_string()),
+                ticker("BTC-USDT", "30000.5", "", 1),
+                ticker("BTC-USDT", "30000.5", "30001.5", 2),
+            ]],
+        )
+        .await;
+
+        wait_for(|| feed.price("BTC-USDT").1 == 2).await;
+        assert_eq!(feed.price("BTC-USDT").0, (30000.5, 30001.5));
+
+        let request = &feed.received()[0][0];
+        assert_eq!(request["op"], "subscribe");
+        assert_eq!(
+            request["args"],
+            json!([{ "channel": "tickers", "instId": "BTC-USDT" }])
+        );
+        feed.stop().await;
+    }
+
+    #[test]
+    fn keeps_the_valid_entries_of_a_message() {
+        let entry = |inst_id: &str, bid: &str| {
+            json!({
+                "instId": inst_id, "bidPx": bid, "bidSz": "0.5",
+                "askPx": "30001.5", "askSz": "1.5", "ts": "1",
+            })
+        };
+        let msg = json!({
+            "arg": { "channel": "tickers", "instId": "BTC-USDT" },
+            "data": [entry("BTC-USDT", ""), entry("BTC-USDC", "30000.5")],
+        });
+        let updates = Okx.parse(&msg.to_string()).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].0, "BTC-USDC");
+    }
+
+    #[test]
+    fn reports_the_failed_requests() {
+        let error =
+            r#"{"event":"error","code":"60012","msg":"Invalid request","connId":"a4d3ae55"}"#;
+        assert!(Okx.parse(error).is_err());
+    }
+}
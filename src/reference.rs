@@ -0,0 +1,833 @@
+use futures::future::BoxFuture;
+use futures_util::{SinkExt, StreamExt};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::error::Error as StdError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
+use tokio::time;
+use tokio_tungstenite::{connect_async, tungstenite, tungstenite::Message};
+use url::Url;
+
+use crate::backoff::Backoff;
+use crate::metrics::METRICS;
+use crate::{bybit_ws::Bybit, coinbase_ws::Coinbase, kraken_ws::Kraken, okx_ws::Okx};
+
+// exchanges drop long lived websocket connections (binance after 24 hours),
+// we reconnect a bit earlier so it happens on our terms.
+const MAX_CONNECTION_AGE: Duration = Duration::from_secs(23 * 60 * 60 + 50 * 60);
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+// a connection which stayed up this long is considered healthy,
+// the backoff restarts from scratch after it drops.
+const HEALTHY_CONNECTION_AGE: Duration = Duration::from_secs(60);
+// the exchanges ping the connection or push heartbeats more often
+// than this, a silent socket for this long is considered dead.
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+// interval of the application level pings, for the exchanges which
+// close the connections without any message from the client.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+// interval at which the prices of the sources are aggregated
+const AGGREGATION_INTERVAL: Duration = Duration::from_millis(200);
+
+/// A source of reference prices, each symbol of the feed updates
+/// its own reference price until shutdown.
+pub trait ReferenceFeed: Send {
+    fn name(&self) -> &'static str;
+
+    /// Stream the prices of the symbols until shutdown.
+    fn start(
+        self: Box<Self>,
+        feeds: HashMap<String, Arc<Mutex<RefPrice>>>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'static, Result<(), Error>>;
+}
+
+/// The websocket API of an exchange. The connection, reconnections
+/// and timeouts are handled by `WsFeed`, the protocols only describe
+/// how to subscribe to the symbols and how to read the messages.
+pub trait WsProtocol: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Messages sent once connected to subscribe to the symbols.
+    fn subscribe(&self, symbols: &[String]) -> Vec<String>;
+
+    /// Application level ping, sent periodically.
+    fn ping(&self) -> Option<String> {
+        return None;
+    }
+
+    /// Read a message into the updates of each symbol, the messages
+    /// without prices (acknowledgments, heartbeats) give no update.
+    fn parse(&self, txt: &str) -> Result<Vec<(String, Update)>, String>;
+}
+
+/// A price level of an order book, or a trade.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Level {
+    pub price: f64,
+    pub quantity: f64,
+}
+
+/// Prices received from a feed for one symbol.
+#[derive(Debug, PartialEq)]
+pub enum Update {
+    /// Best bid and ask, the event time is reported by some exchanges only
+    TopOfBook {
+        bid: Level,
+        ask: Level,
+        event_time: Option<u64>,
+    },
+    /// Best levels of the book, neither side is empty
+    Depth {
+        bids: Vec<Level>,
+        asks: Vec<Level>,
+    },
+    Trade {
+        trade: Level,
+        event_time: u64,
+    },
+}
+
+pub struct RefPrice {
+    bid_price: f64,
+    ask_price: f64,
+    // quantities at the best bid and ask, 0 if the stream does not report them
+    bid_quantity: f64,
+    ask_quantity: f64,
+    mid_price: f64,
+    // best levels first, depth streams only
+    bids: Vec<Level>,
+    asks: Vec<Level>,
+    // trade streams only
+    last_trade: Option<Level>,
+    // event time reported by the exchange, or the local time for the
+    // streams which do not report it, in milliseconds since epoch
+    event_time: u64,
+    // local time at which the last update was received
+    received_at: Option<Instant>,
+}
+
+impl RefPrice {
+    pub fn new() -> RefPrice {
+        return RefPrice {
+            bid_price: 0.,
+            ask_price: 0.,
+            bid_quantity: 0.,
+            ask_quantity: 0.,
+            mid_price: 0.,
+            bids: vec![],
+            asks: vec![],
+            last_trade: None,
+            event_time: 0,
+            received_at: None,
+        };
+    }
+
+    pub fn set(&mut self, bid_price: f64, ask_price: f64, event_time: u64) {
+        let level = |price| Level {
+            price,
+            quantity: 0.,
+        };
+        self.set_top_of_book(level(bid_price), level(ask_price), event_time);
+    }
+
+    pub fn apply(&mut self, update: Update) {
+        match update {
+            Update::TopOfBook {
+                bid,
+                ask,
+                event_time,
+            } => self.set_top_of_book(bid, ask, event_time.unwrap_or_else(now_millis)),
+            Update::Depth { bids, asks } => self.set_depth(bids, asks, now_millis()),
+            Update::Trade { trade, event_time } => self.set_last_trade(trade, event_time),
+        }
+    }
+
+    /// Update from the best bid and ask, the mid price is their average.
+    pub fn set_top_of_book(&mut self, bid: Level, ask: Level, event_time: u64) {
+        self.bid_price = bid.price;
+        self.ask_price = ask.price;
+        self.bid_quantity = bid.quantity;
+        self.ask_quantity = ask.quantity;
+        self.mid_price = (bid.price + ask.price) / 2.;
+        self.bids = vec![];
+        self.asks = vec![];
+        self.last_trade = None;
+        self.received(event_time);
+    }
+
+    /// Update from the best levels of the book, neither side can be empty.
+    /// The mid price is the average of the quantity weighted price of each side.
+    pub fn set_depth(&mut self, bids: Vec<Level>, asks: Vec<Level>, event_time: u64) {
+        self.bid_price = bids[0].price;
+        self.ask_price = asks[0].price;
+        self.bid_quantity = bids[0].quantity;
+        self.ask_quantity = asks[0].quantity;
+        self.mid_price = (weighted_price(&bids) + weighted_price(&asks)) / 2.;
+        self.bids = bids;
+        self.asks = asks;
+        self.last_trade = None;
+        self.received(event_time);
+    }
+
+    /// Update from a trade, the bid, ask and mid prices are the trade price.
+    pub fn set_last_trade(&mut self, trade: Level, event_time: u64) {
+        self.bid_price = trade.price;
+        self.ask_price = trade.price;
+        self.bid_quantity = 0.;
+        self.ask_quantity = 0.;
+        self.mid_price = trade.price;
+        self.bids = vec![];
+        self.asks = vec![];
+        self.last_trade = Some(trade);
+        self.received(event_time);
+    }
+
+    /// Update from the aggregated prices of several sources.
+    pub fn set_aggregated(&mut self, bid: Level, ask: Level, mid_price: f64, event_time: u64) {
+        self.set_top_of_book(bid, ask, event_time);
+        self.mid_price = mid_price;
+    }
+
+    fn received(&mut self, event_time: u64) {
+        self.event_time = event_time;
+        self.received_at = Some(Instant::now());
+    }
+
+    pub fn get(&self) -> (f64, f64) {
+        return (self.bid_price, self.ask_price);
+    }
+
+    pub fn get_quantities(&self) -> (f64, f64) {
+        return (self.bid_quantity, self.ask_quantity);
+    }
+
+    pub fn mid_price(&self) -> f64 {
+        return self.mid_price;
+    }
+
+    /// Bid and ask levels, empty unless a depth stream is used.
+    pub fn depth(&self) -> (&[Level], &[Level]) {
+        return (&self.bids, &self.asks);
+    }
+
+    pub fn last_trade(&self) -> Option<Level> {
+        return self.last_trade;
+    }
+
+    pub fn event_time(&self) -> u64 {
+        return self.event_time;
+    }
+
+    /// Time elapsed since the last update was received,
+    /// None if no update was received yet.
+    pub fn age(&self) -> Option<Duration> {
+        return self.received_at.map(|t| t.elapsed());
+    }
+
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        match self.age() {
+            Some(age) => age > max_age,
+            None => true,
+        }
+    }
+}
+
+fn weighted_price(levels: &[Level]) -> f64 {
+    let quantity: f64 = levels.iter().map(|l| l.quantity).sum();
+    if quantity == 0. {
+        return levels[0].price;
+    }
+    return levels.iter().map(|l| l.price * l.quantity).sum::<f64>() / quantity;
+}
+
+/// A reference feed streaming the prices of all its symbols
+/// over a single websocket connection.
+pub struct WsFeed {
+    protocol: Box<dyn WsProtocol>,
+    ws_url: String,
+}
+
+impl WsFeed {
+    pub fn new(protocol: Box<dyn WsProtocol>, ws_url: String) -> WsFeed {
+        return WsFeed { protocol, ws_url };
+    }
+}
+
+impl ReferenceFeed for WsFeed {
+    fn name(&self) -> &'static str {
+        return self.protocol.name();
+    }
+
+    fn start(
+        self: Box<Self>,
+        feeds: HashMap<String, Arc<Mutex<RefPrice>>>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        return Box::pin(stream_ws(self.protocol, self.ws_url, feeds, shutdown));
+    }
+}
+
+// keep the websocket connected until shutdown, reconnecting
+// with backoff every time it drops.
+async fn stream_ws(
+    feed: Box<dyn WsProtocol>,
+    ws_url: String,
+    feeds: HashMap<String, Arc<Mutex<RefPrice>>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let url = ws_url.parse::<Url>()?;
+    let mut backoff = Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY);
+    loop {
+        let connected_at = Instant::now();
+        let res = tokio::select! {
+            res = run(feed.as_ref(), &url, &feeds) => res,
+            _ = shutdown.changed() => {
+                info!("shutting down {} feed", feed.name());
+                return Ok(());
+            }
+        };
+
+        if let Err(e) = res {
+            if connected_at.elapsed() >= HEALTHY_CONNECTION_AGE {
+                backoff.reset();
+            }
+            let delay = backoff.next_delay();
+            warn!(
+                "{} feed disconnected ({}), reconnecting in {:?} (attempt {})",
+                feed.name(),
+                e,
+                delay,
+                backoff.attempt(),
+            );
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = shutdown.changed() => {
+                    info!("shutting down {} feed", feed.name());
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+async fn run(
+    feed: &dyn WsProtocol,
+    url: &Url,
+    feeds: &HashMap<String, Arc<Mutex<RefPrice>>>,
+) -> Result<(), Error> {
+    let name = feed.name();
+    info!("opening websocket with {} API at: {}", name, url);
+    let (mut socket, _) = connect_async(url.clone()).await?;
+    info!("connected to {} successfully", name);
+
+    let symbols: Vec<String> = feeds.keys().cloned().collect();
+    for request in feed.subscribe(&symbols) {
+        socket.send(Message::Text(request)).await?;
+    }
+
+    let max_age = time::sleep(MAX_CONNECTION_AGE);
+    tokio::pin!(max_age);
+    let mut ping = time::interval_at(time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+    loop {
+        // pings are answered by tungstenite while reading,
+        // the pong is flushed on the next poll of the socket.
+        let next = tokio::select! {
+            next = time::timeout(READ_TIMEOUT, socket.next()) => next,
+            _ = ping.tick() => {
+                if let Some(ping) = feed.ping() {
+                    socket.send(Message::Text(ping)).await?;
+                }
+                continue;
+            }
+            _ = &mut max_age => {
+                let _ = socket.close(None).await;
+                return Err(Error::MaxConnectionAge);
+            }
+        };
+
+        let msg = match next {
+            Ok(Some(msg)) => msg?,
+            Ok(None) => return Err(Error::Closed("end of stream".to_string())),
+            Err(_) => return Err(Error::Timeout),
+        };
+
+        let txt = match msg {
+            Message::Text(txt) => {
+                METRICS.inc_reference_messages(name);
+                txt
+            }
+            Message::Close(frame) => {
+                return Err(Error::Closed(
+                    frame.map(|f| f.to_string()).unwrap_or_default(),
+                ))
+            }
+            _ => continue,
+        };
+
+        // keep the previous prices on an invalid update,
+        // they become stale if no valid update follows.
+        let updates = match feed.parse(&txt) {
+            Ok(updates) => updates,
+            Err(e) => {
+                warn!("invalid {} message, skipping: {}", name, e);
+                continue;
+            }
+        };
+        for (symbol, update) in updates {
+            if let Some(rp) = feeds.get(&symbol) {
+                debug!("new {} prices for {}: {:?}", name, symbol, update);
+                rp.lock().unwrap().apply(update);
+            }
+        }
+    }
+}
+
+/// A price level from a price and a quantity as sent by the exchanges.
+/// A price is only valid if it is a positive finite number, and a
+/// quantity if it is a finite number, positive or zero.
+pub fn parse_level(price: &str, quantity: &str) -> Option<Level> {
+    return level(price.parse::<f64>().ok()?, quantity.parse::<f64>().ok()?);
+}
+
+/// Same as `parse_level`, for the exchanges sending numbers.
+pub fn level(price: f64, quantity: f64) -> Option<Level> {
+    if !price.is_finite() || price <= 0. || !quantity.is_finite() || quantity < 0. {
+        return None;
+    }
+    return Some(Level { price, quantity });
+}
+
+pub fn now_millis() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+}
+
+/// Exchanges the reference price can be read from, besides binance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Exchange {
+    Coinbase,
+    Kraken,
+    Bybit,
+    Okx,
+}
+
+impl Exchange {
+    pub fn default_ws_url(&self) -> &'static str {
+        use Exchange::*;
+        match self {
+            Coinbase => "wss://ws-feed.exchange.coinbase.com",
+            Kraken => "wss://ws.kraken.com/v2",
+            Bybit => "wss://stream.bybit.com/v5/public/spot",
+            Okx => "wss://ws.okx.com:8443/ws/v5/public",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        return self.protocol().name();
+    }
+
+    pub fn protocol(&self) -> Box<dyn WsProtocol> {
+        match self {
+            Exchange::Coinbase => Box::new(Coinbase),
+            Exchange::Kraken => Box::new(Kraken),
+            Exchange::Bybit => Box::new(Bybit),
+            Exchange::Okx => Box::new(Okx),
+        }
+    }
+}
+
+/// An exchange the reference price is read from, the symbol
+/// of each market on this exchange is set in the markets.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SourceConfig {
+    pub exchange: Exchange,
+    // defaults to the public endpoint of the exchange
+    pub ws_url: Option<String>,
+}
+
+impl SourceConfig {
+    pub fn ws_url(&self) -> String {
+        return match &self.ws_url {
+            Some(url) => url.clone(),
+            None => self.exchange.default_ws_url().to_string(),
+        };
+    }
+
+    pub fn feed(&self) -> Box<dyn ReferenceFeed> {
+        return Box::new(WsFeed::new(self.exchange.protocol(), self.ws_url()));
+    }
+}
+
+/// How the prices of the sources are combined into the reference price.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    /// Median of the prices
+    #[default]
+    Median,
+    /// Average of the prices weighted by the quantity at the best bid
+    /// and ask of each source, the median if no source reports them
+    VolumeWeighted,
+}
+
+/// The reference price of a market on one exchange.
+#[derive(Clone)]
+pub struct Source {
+    pub name: String,
+    pub rp: Arc<Mutex<RefPrice>>,
+}
+
+pub struct AggregatorConfig {
+    pub aggregation: Aggregation,
+    // sources not updated for this long are dropped
+    pub max_age: Duration,
+    // sources deviating from the median of all the sources by more
+    // than this are dropped, in basis points, when there are at least 3.
+    // With less, a deviating source gives no price at all.
+    pub max_deviation_bps: u64,
+}
+
+// minimum number of usable sources for the outliers to be dropped
+const MIN_SOURCES_FOR_OUTLIERS: usize = 3;
+
+/// Result of the aggregation of the sources.
+#[derive(Debug, PartialEq)]
+pub struct Aggregate {
+    pub bid: Level,
+    pub ask: Level,
+    pub mid_price: f64,
+    pub event_time: u64,
+    // names of the sources dropped, and why
+    pub dropped: Vec<(String, &'static str)>,
+}
+
+// prices of a usable source
+struct SourcePrice {
+    name: String,
+    bid: Level,
+    ask: Level,
+    mid_price: f64,
+    event_time: u64,
+}
+
+/// Combine the prices of the sources, None if no source is usable
+/// or if less than 3 sources are usable and they disagree.
+pub fn aggregate(sources: &[Source], config: &AggregatorConfig) -> Option<Aggregate> {
+    let mut dropped = vec![];
+    let mut prices = vec![];
+    for s in sources.iter() {
+        let rp = s.rp.lock().unwrap();
+        if rp.is_stale(config.max_age) {
+            dropped.push((s.name.clone(), "stale"));
+            continue;
+        }
+        let (bid, ask) = rp.get();
+        let (bid_quantity, ask_quantity) = rp.get_quantities();
+        match (level(bid, bid_quantity), level(ask, ask_quantity)) {
+            (Some(bid), Some(ask)) => prices.push(SourcePrice {
+                name: s.name.clone(),
+                bid,
+                ask,
+                mid_price: rp.mid_price(),
+                event_time: rp.event_time(),
+            }),
+            _ => dropped.push((s.name.clone(), "invalid")),
+        }
+    }
+
+    let median_mid = median(prices.iter().map(|p| p.mid_price).collect())?;
+    let usable = prices.len();
+    let (kept, outliers): (Vec<_>, Vec<_>) = prices.into_iter().partition(|p| {
+        let deviation_bps = (p.mid_price - median_mid).abs() / median_mid * 10_000.;
+        deviation_bps <= config.max_deviation_bps as f64
+    });
+    // with less than 3 sources there is no majority to tell the
+    // outlier, the price is unknown until the sources agree again
+    if usable < MIN_SOURCES_FOR_OUTLIERS && !outliers.is_empty() {
+        return None;
+    }
+    dropped.extend(outliers.into_iter().map(|p| (p.name, "outlier")));
+
+    let total_quantity: f64 = kept.iter().map(|p| p.bid.quantity + p.ask.quantity).sum();
+    let combine = |price: fn(&SourcePrice) -> f64| -> Option<f64> {
+        if config.aggregation == Aggregation::VolumeWeighted && total_quantity > 0. {
+            let sum: f64 = kept
+                .iter()
+                .map(|p| price(p) * (p.bid.quantity + p.ask.quantity))
+                .sum();
+            return Some(sum / total_quantity);
+        }
+        return median(kept.iter().map(price).collect());
+    };
+
+    return Some(Aggregate {
+        bid: Level {
+            price: combine(|p| p.bid.price)?,
+            quantity: kept.iter().map(|p| p.bid.quantity).sum(),
+        },
+        ask: Level {
+            price: combine(|p| p.ask.price)?,
+            quantity: kept.iter().map(|p| p.ask.quantity).sum(),
+        },
+        mid_price: combine(|p| p.mid_price)?,
+        event_time: kept.iter().map(|p| p.event_time).max()?,
+        dropped,
+    });
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let n = values.len();
+    if n % 2 == 0 {
+        return Some((values[n / 2 - 1] + values[n / 2]) / 2.);
+    }
+    return Some(values[n / 2]);
+}
+
+/// Update the reference price of a market from its sources until shutdown.
+/// The reference price is left untouched while no source is usable, so
+/// it becomes stale and the strategy stops trading.
+pub async fn aggregate_forever(
+    sources: Vec<Source>,
+    rp: Arc<Mutex<RefPrice>>,
+    config: AggregatorConfig,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut interval = time::interval(AGGREGATION_INTERVAL);
+    let mut last_dropped = BTreeSet::new();
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => return,
+        }
+
+        let aggregate = aggregate(&sources, &config);
+
+        // only log the changes, the aggregation runs several times per second
+        let dropped: BTreeSet<(String, &str)> = match &aggregate {
+            Some(a) => a.dropped.iter().cloned().collect(),
+            None => sources
+                .iter()
+                .map(|s| (s.name.clone(), "unusable"))
+                .collect(),
+        };
+        if dropped != last_dropped {
+            match dropped.is_empty() {
+                true => info!("all reference price sources are used"),
+                false => warn!("reference price sources dropped: {:?}", dropped),
+            }
+            last_dropped = dropped;
+        }
+
+        if let Some(a) = aggregate {
+            rp.lock()
+                .unwrap()
+                .set_aggregated(a.bid, a.ask, a.mid_price, a.event_time);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    WSError(tungstenite::Error),
+    UrlError(url::ParseError),
+    Closed(String),
+    Timeout,
+    MaxConnectionAge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "reference feed error: {}", self.desc())
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(error: tungstenite::Error) -> Self {
+        Error::WSError(error)
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(error: url::ParseError) -> Self {
+        Error::UrlError(error)
+    }
+}
+
+impl StdError for Error {}
+
+impl Error {
+    pub fn desc(&self) -> String {
+        use Error::*;
+        match self {
+            WSError(e) => format!("websocket error: {}", e),
+            UrlError(e) => format!("invalid url: {}", e),
+            Closed(reason) => format!("connection closed by server: {}", reason),
+            Timeout => format!("no message received for {:?}", READ_TIMEOUT),
+            MaxConnectionAge => format!("connection reached its maximum age"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::wait_for;
+
+    fn source(name: &str, bid: (f64, f64), ask: (f64, f64)) -> Source {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        if bid.0 > 0. {
+            rp.lock().unwrap().set_top_of_book(
+                Level {
+                    price: bid.0,
+                    quantity: bid.1,
+                },
+                Level {
+                    price: ask.0,
+                    quantity: ask.1,
+                },
+                1,
+            );
+        }
+        return Source {
+            name: name.to_string(),
+            rp,
+        };
+    }
+
+    fn config(aggregation: Aggregation) -> AggregatorConfig {
+        return AggregatorConfig {
+            aggregation,
+            max_age: Duration::from_secs(10),
+            max_deviation_bps: 100,
+        };
+    }
+
+    #[test]
+    fn takes_the_median_of_the_sources() {
+        let sources = vec![
+            source("binance", (99., 1.), (101., 1.)),
+            source("coinbase", (100., 1.), (102., 1.)),
+            source("kraken", (101., 1.), (103., 1.)),
+        ];
+
+        let a = aggregate(&sources, &config(Aggregation::Median)).unwrap();
+        assert_eq!(a.bid.price, 100.);
+        assert_eq!(a.ask.price, 102.);
+        assert_eq!(a.mid_price, 101.);
+        assert_eq!(a.bid.quantity, 3.);
+        assert!(a.dropped.is_empty());
+    }
+
+    #[test]
+    fn weights_the_sources_by_their_quantities() {
+        let sources = vec![
+            source("binance", (99., 3.), (101., 3.)),
+            source("coinbase", (100., 1.), (102., 1.)),
+        ];
+
+        let a = aggregate(&sources, &config(Aggregation::VolumeWeighted)).unwrap();
+        // (100 * 6 + 101 * 2) / 8
+        assert_eq!(a.mid_price, 100.25);
+
+        // no quantities, falls back to the median
+        let sources = vec![
+            source("binance", (99., 0.), (101., 0.)),
+            source("coinbase", (100., 0.), (102., 0.)),
+        ];
+        let a = aggregate(&sources, &config(Aggregation::VolumeWeighted)).unwrap();
+        assert_eq!(a.mid_price, 100.5);
+    }
+
+    #[test]
+    fn drops_the_outliers_and_the_stale_sources() {
+        let sources = vec![
+            source("binance", (99., 1.), (101., 1.)),
+            source("coinbase", (100., 1.), (102., 1.)),
+            // 10% above the others
+            source("kraken", (110., 1.), (112., 1.)),
+            // never updated
+            source("okx", (0., 0.), (0., 0.)),
+        ];
+
+        let a = aggregate(&sources, &config(Aggregation::Median)).unwrap();
+        assert_eq!(a.mid_price, 100.5);
+        assert_eq!(
+            a.dropped,
+            vec![
+                ("okx".to_string(), "stale"),
+                ("kraken".to_string(), "outlier"),
+            ]
+        );
+    }
+
+    #[test]
+    fn gives_no_price_when_two_sources_disagree() {
+        let sources = vec![
+            source("binance", (99., 1.), (101., 1.)),
+            source("coinbase", (109., 1.), (111., 1.)),
+        ];
+        assert!(aggregate(&sources, &config(Aggregation::Median)).is_none());
+
+        // within the deviation, 50bps from the median
+        sources[1].rp.lock().unwrap().set(100., 102., 2);
+        let a = aggregate(&sources, &config(Aggregation::Median)).unwrap();
+        assert_eq!(a.mid_price, 100.5);
+        assert!(a.dropped.is_empty());
+
+        // one of them stale, the other one is used alone
+        let sources = vec![
+            source("binance", (99., 1.), (101., 1.)),
+            source("coinbase", (0., 0.), (0., 0.)),
+        ];
+        let a = aggregate(&sources, &config(Aggregation::Median)).unwrap();
+        assert_eq!(a.mid_price, 100.);
+    }
+
+    #[test]
+    fn gives_no_price_when_the_sources_disagree() {
+        let sources = vec![
+            source("binance", (99., 1.), (101., 1.)),
+            source("coinbase", (109., 1.), (111., 1.)),
+            source("kraken", (119., 1.), (121., 1.)),
+            source("okx", (129., 1.), (131., 1.)),
+        ];
+        assert!(aggregate(&sources, &config(Aggregation::Median)).is_none());
+        assert!(aggregate(&[], &config(Aggregation::Median)).is_none());
+    }
+
+    #[tokio::test]
+    async fn updates_the_reference_price_of_the_market() {
+        let sources = vec![
+            source("binance", (99., 1.), (101., 1.)),
+            source("coinbase", (0., 0.), (0., 0.)),
+        ];
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let handle = tokio::spawn(aggregate_forever(
+            sources.clone(),
+            rp.clone(),
+            config(Aggregation::Median),
+            shutdown_rx,
+        ));
+
+        wait_for(|| rp.lock().unwrap().mid_price() == 100.).await;
+        sources[1].rp.lock().unwrap().set(101., 103., 2);
+        wait_for(|| rp.lock().unwrap().mid_price() == 101.).await;
+        assert_eq!(rp.lock().unwrap().event_time(), 2);
+
+        shutdown.send(true).unwrap();
+        handle.await.unwrap();
+    }
+}
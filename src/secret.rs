@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::fs::PermissionsExt;
+
+/// A secret value, redacted from the Debug output.
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        return &self.0;
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+/// Where a secret is read from:
+/// - a plain string, directly in the configuration
+/// - `{ "env": "NAME" }`: the environment variable NAME
+/// - `{ "file": "path" }`: a file only accessible by its owner
+/// - `{ "keystore": "path", "passphrase_env": "NAME" }`: a file encrypted
+///   with `age --passphrase`, the passphrase is read from the environment
+///   variable NAME if set, or prompted on the terminal
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum SecretSource {
+    Plain(String),
+    Env {
+        env: String,
+    },
+    File {
+        file: String,
+    },
+    Keystore {
+        keystore: String,
+        passphrase_env: Option<String>,
+    },
+}
+
+impl fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SecretSource::*;
+        match self {
+            Plain(_) => write!(f, "Plain(<redacted>)"),
+            Env { env } => write!(f, "Env({})", env),
+            File { file } => write!(f, "File({})", file),
+            Keystore { keystore, .. } => write!(f, "Keystore({})", keystore),
+        }
+    }
+}
+
+impl SecretSource {
+    pub fn load(&self) -> Result<Secret, Error> {
+        use SecretSource::*;
+        let value = match self {
+            Plain(value) => value.clone(),
+            Env { env } => std::env::var(env).map_err(|_| Error::MissingEnv(env.clone()))?,
+            File { file } => read_private_file(file)?,
+            Keystore {
+                keystore,
+                passphrase_env,
+            } => decrypt_keystore(keystore, passphrase_env.as_deref())?,
+        };
+
+        return Ok(Secret(value.trim().to_string()));
+    }
+}
+
+fn read_private_file(path: &str) -> Result<String, Error> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(Error::Permissions(path.to_string(), mode & 0o777));
+    }
+    return Ok(fs::read_to_string(path)?);
+}
+
+fn decrypt_keystore(path: &str, passphrase_env: Option<&str>) -> Result<String, Error> {
+    let passphrase = match passphrase_env {
+        Some(env) => std::env::var(env).map_err(|_| Error::MissingEnv(env.to_string()))?,
+        None => rpassword::prompt_password(format!("passphrase for {}: ", path))?,
+    };
+
+    let encrypted = fs::read(path)?;
+    let decryptor = match age::Decryptor::new(&encrypted[..])? {
+        age::Decryptor::Passphrase(d) => d,
+        _ => {
+            return Err(Error::Keystore(
+                "not a passphrase encrypted file".to_string(),
+            ))
+        }
+    };
+
+    let mut decrypted = String::new();
+    decryptor
+        .decrypt(&age::secrecy::Secret::new(passphrase), None)?
+        .read_to_string(&mut decrypted)?;
+    return Ok(decrypted);
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MissingEnv(String),
+    Permissions(String, u32),
+    Keystore(String),
+    IoError(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unable to load secret: {}", self.desc())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::IoError(error)
+    }
+}
+
+impl From<age::DecryptError> for Error {
+    fn from(error: age::DecryptError) -> Self {
+        Error::Keystore(error.to_string())
+    }
+}
+
+impl StdError for Error {}
+
+impl Error {
+    pub fn desc(&self) -> String {
+        use Error::*;
+        match self {
+            MissingEnv(env) => format!("environment variable {} is not set", env),
+            Permissions(path, mode) => format!(
+                "{} has mode {:o}, it must only be accessible by its owner (chmod 600)",
+                path, mode
+            ),
+            Keystore(e) => format!("keystore error: {}", e),
+            IoError(e) => format!("io error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const MNEMONIC: &str = "tiger harbor velvet";
+
+    #[test]
+    fn redacts_the_plain_secrets() {
+        let source = SecretSource::Plain(MNEMONIC.to_string());
+        assert!(!format!("{:?}", source).contains(MNEMONIC));
+        assert!(!format!("{:?}", source.load().unwrap()).contains(MNEMONIC));
+    }
+
+    fn private_file(mode: u32) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "{}", MNEMONIC).unwrap();
+        fs::set_permissions(file.path(), fs::Permissions::from_mode(mode)).unwrap();
+        return file;
+    }
+
+    #[test]
+    fn reads_a_file_only_accessible_by_its_owner() {
+        let file = private_file(0o600);
+        let source = SecretSource::File {
+            file: file.path().to_str().unwrap().to_string(),
+        };
+        assert_eq!(source.load().unwrap().expose(), MNEMONIC);
+    }
+
+    #[test]
+    fn refuses_a_file_accessible_by_others() {
+        for mode in [0o644, 0o640] {
+            let file = private_file(mode);
+            let path = file.path().to_str().unwrap();
+            match read_private_file(path) {
+                Err(Error::Permissions(p, m)) => {
+                    assert_eq!(p, path);
+                    assert_eq!(m, mode);
+                }
+                other => panic!("expected a permissions error, got {:?}", other),
+            }
+        }
+    }
+}
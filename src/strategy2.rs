@@ -1,59 +1,256 @@
-use log::info;
-use num_bigint::BigUint;
-use num_traits::cast::FromPrimitive;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
 use tokio::time;
-use vega_crypto::Transact;
 use vega_protobufs::vega::{
-    commands::v1::{
-        input_data::Command, BatchMarketInstructions, OrderCancellation, OrderSubmission,
-    },
+    commands::v1::{BatchMarketInstructions, OrderCancellation, OrderSubmission},
     instrument::Product,
-    order::{TimeInForce, Type},
-    Market, Side,
+    order::{Status as OrderStatus, TimeInForce, Type},
+    AccountType, Market, MarketData, OrderError, Side,
 };
 use vega_protobufs::vega::{Asset, Position};
 
-use crate::{binance_ws::RefPrice, vega_store2::VegaStore};
+use crate::{
+    cross_mid::CrossMid, metrics::METRICS, reference::RefPrice, vega_store2::VegaStore,
+    wallet::Wallet,
+};
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub struct StrategyConfig {
+    pub strategy: StrategyKind,
+    pub market: String,
+    pub trade_size: i64,
+    pub submission_rate: u64,
+    // maximum age of the reference price before a cycle is skipped
+    pub max_ref_price_age: Duration,
+    // maximum deviation allowed between the vega price and the
+    // reference price, in basis points, None disables the check
+    pub max_ref_price_deviation_bps: Option<u64>,
+    pub out_of_band_action: OutOfBandAction,
+    pub pricing_mode: PricingMode,
+    pub one_sided_book_fallback: BookFallback,
+    pub counterparty_selection: CounterpartySelection,
+    // minimum general account balance, in asset units, each
+    // wallet must hold to trade, None disables the check
+    pub min_general_balance: Option<f64>,
+    // close the positions of the wallets with market orders on shutdown
+    pub flatten_on_shutdown: bool,
+    // maximum time to wait for the orders and positions to be
+    // closed on shutdown
+    pub shutdown_timeout: Duration,
+}
+
+/// Outcome of the last strategy cycle.
+#[derive(Debug, Clone, Serialize)]
+pub struct Decision {
+    pub cycle: u64,
+    // seconds since epoch
+    pub timestamp: u64,
+    pub traded: bool,
+    // why the cycle was skipped, empty if it traded
+    pub reason: String,
+    pub price: Option<String>,
+    // signed order size sent by each wallet, by public key
+    pub order_sizes: BTreeMap<String, i64>,
+}
+
+impl Decision {
+    fn skipped(cycle: u64, reason: &str) -> Decision {
+        return Decision {
+            cycle,
+            timestamp: now_secs(),
+            traded: false,
+            reason: reason.to_string(),
+            price: None,
+            order_sizes: BTreeMap::new(),
+        };
+    }
+
+    fn traded(cycle: u64, price: String, order_sizes: BTreeMap<String, i64>) -> Decision {
+        return Decision {
+            cycle,
+            timestamp: now_secs(),
+            traded: true,
+            reason: "".to_string(),
+            price: Some(price),
+            order_sizes,
+        };
+    }
+}
+
+fn now_secs() -> u64 {
+    return SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+}
+
+/// Price used in place of the vega mid price when the
+/// vega order book is empty or one-sided.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookFallback {
+    /// Do not trade this cycle
+    #[default]
+    Skip,
+    /// Use the binance mid price
+    BinanceMid,
+    /// Use the vega mark price
+    MarkPrice,
+}
+
+/// Source of the price used for the cross trade.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PricingMode {
+    /// Mid price of the vega order book
+    #[default]
+    VegaMid,
+    /// Mid price of the binance reference feed
+    BinanceMid,
+    /// Mark price of the vega market
+    MarkPrice,
+    /// Weighted average of the three prices above
+    Blend {
+        vega_mid: f64,
+        binance_mid: f64,
+        mark_price: f64,
+    },
+}
+
+/// How the two wallets trading against each other
+/// are picked from the pool every cycle.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CounterpartySelection {
+    /// Each wallet trades with the next one, moving along the pool every cycle
+    #[default]
+    Rotation,
+    /// The most long wallet trades with the most short one, bringing
+    /// both positions back toward zero
+    NetExposure,
+}
+
+/// What to do when the vega price is outside of the band
+/// allowed around the reference price.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutOfBandAction {
+    /// Do not trade this cycle
+    #[default]
+    Skip,
+    /// Trade at the closest price still inside the band
+    Clamp,
+}
+
+/// Name of the strategy run by the bot.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrategyKind {
+    /// Cross trade between the wallets at the price of the pricing mode
+    #[default]
+    CrossMid,
+}
+
+impl StrategyKind {
+    pub fn build(&self) -> Box<dyn Strategy> {
+        return match self {
+            StrategyKind::CrossMid => Box::new(CrossMid),
+        };
+    }
+}
+
+/// A trading strategy, called once per cycle with a snapshot of
+/// the market and the wallets. It only decides which orders to send,
+/// the scheduling loop sends them.
+pub trait Strategy: Send {
+    fn decide(&mut self, config: &StrategyConfig, snapshot: &Snapshot) -> Intent;
+}
+
+/// Orders a strategy wants sent during a cycle.
+pub enum Intent {
+    /// Do not trade this cycle, the reason is used as a metric label
+    Skip(&'static str),
+    /// Send the batches, in order
+    Trade {
+        price: String,
+        batches: Vec<WalletBatch>,
+    },
+}
+
+pub struct WalletBatch {
+    // index of the wallet in the snapshot
+    pub wallet: usize,
+    // signed size of the order, negative when selling
+    pub size: i64,
+    pub batch: BatchMarketInstructions,
+}
+
+/// Read-only state given to a strategy each cycle.
+pub struct Snapshot {
+    pub cycle: u64,
+    pub market: Market,
+    pub market_data: MarketData,
+    pub asset: Asset,
+    pub decimals: Decimals,
+    pub ref_bid: f64,
+    pub ref_ask: f64,
+    // depends on the binance stream and the aggregation of the sources
+    pub ref_mid: f64,
+    pub ref_price_age: Option<Duration>,
+    pub wallets: Vec<WalletState>,
+}
+
+impl Snapshot {
+    pub fn ref_mid_price(&self) -> f64 {
+        return self.ref_mid;
+    }
+}
+
+pub struct WalletState {
+    pub name: String,
+    pub pubkey: String,
+    pub open_volume: i64,
+    // balances in asset units
+    pub general_balance: f64,
+    pub margin_balance: f64,
+    pub bond_balance: f64,
+    // the general balance is below the configured minimum,
+    // the wallet must not trade
+    pub low_balance: bool,
+}
 
 pub async fn start(
-    mut w1: Transact,
-    mut w2: Transact,
-    default_trade_size: i64,
-    market: String,
+    mut wallets: Vec<Wallet>,
+    config: StrategyConfig,
     store: Arc<Mutex<VegaStore>>,
     rp: Arc<Mutex<RefPrice>>,
-    submission_rate: u64,
+    last_decision: Arc<Mutex<Option<Decision>>>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
-    // just loop forever, waiting for user interupt
+    // loop until the shutdown signal is received
     info!(
         "starting with submission rate of {} seconds",
-        submission_rate
+        config.submission_rate
     );
+    let market = config.market.clone();
+    let mut strategy = config.strategy.build();
 
     info!("closing all positions");
-    match w1
-        .send(Command::BatchMarketInstructions(get_close_batch(
-            market.clone(),
-        )))
-        .await
-    {
-        Ok(o) => info!("w1 close batch result: {:?}", o),
-        Err(e) => info!("w1 close batch transaction error: {:?}", e),
-    };
-
-    match w2
-        .send(Command::BatchMarketInstructions(get_close_batch(
-            market.clone(),
-        )))
-        .await
-    {
-        Ok(o) => info!("w2 close batch result: {:?}", o),
-        Err(e) => info!("w2 close batch transaction error: {:?}", e),
-    };
+    for (i, w) in wallets.iter_mut().enumerate() {
+        let name = format!("w{} close batch", i + 1);
+        w.send_batch(&name, get_close_batch(market.clone())).await;
+    }
 
-    let mut interval = time::interval(Duration::from_secs(submission_rate));
+    let mut interval = time::interval(Duration::from_secs(config.submission_rate));
+    let mut cycle: u64 = 0;
+    // reference of the orders sent during the last cycle which
+    // traded, and the indexes of the wallets which sent them
+    let mut last_orders: Option<(String, Vec<usize>)> = None;
     loop {
         tokio::select! {
             _ = interval.tick() => {
@@ -61,28 +258,109 @@ pub async fn start(
                 let extra_sleep = rand::random::<u64>() % 10;
                 info!("adding extra sleep of {} seconds before starting", extra_sleep);
                 // add some extra time here jsut to look a little bit less scripted
-                time::sleep(Duration::from_secs(extra_sleep)).await;
-                run_strategy(&mut w1, &mut w2, market.clone(), store.clone(), rp.clone(), default_trade_size).await;
+                tokio::select! {
+                    _ = time::sleep(Duration::from_secs(extra_sleep)) => {}
+                    _ = shutdown.changed() => break,
+                }
+                cycle += 1;
+                let decision = run_strategy(strategy.as_mut(), &mut wallets, &config, store.clone(), rp.clone(), cycle, &mut last_orders).await;
+                METRICS.inc_cycles_run(&market);
+                if !decision.traded {
+                    METRICS.inc_cycles_skipped(&market, &decision.reason);
+                }
+                *last_decision.lock().unwrap() = Some(decision);
             }
+            _ = shutdown.changed() => break,
         }
     }
+
+    info!("shutdown requested, stopping the strategy");
+    shutdown_wallets(&mut wallets, &config, &store).await;
+}
+
+// cancel the orders of all the wallets, optionally flatten their positions,
+// then wait for the datanode to confirm it or for the shutdown timeout.
+async fn shutdown_wallets(
+    wallets: &mut [Wallet],
+    config: &StrategyConfig,
+    store: &Arc<Mutex<VegaStore>>,
+) {
+    let market = config.market.clone();
+    info!("cancelling all orders");
+    for (i, w) in wallets.iter_mut().enumerate() {
+        let name = format!("w{} close batch", i + 1);
+        w.send_batch(&name, get_close_batch(market.clone())).await;
+    }
+
+    if config.flatten_on_shutdown {
+        for (i, w) in wallets.iter_mut().enumerate() {
+            let name = format!("w{} flatten batch", i + 1);
+            let open_volume = w.open_volume(store);
+            if open_volume != 0 {
+                info!("{}: closing open volume of {}", name, open_volume);
+                w.send_batch(&name, get_flatten_batch(market.clone(), open_volume))
+                    .await;
+            }
+        }
+    }
+
+    // the datanode never sees the orders of dry run wallets
+    if wallets.iter().all(|w| w.is_dry_run()) {
+        info!("dry run, not waiting for the datanode, shutdown complete");
+        return;
+    }
+
+    let deadline = time::Instant::now() + config.shutdown_timeout;
+    loop {
+        let pending: Vec<String> = wallets
+            .iter()
+            .filter(|w| {
+                let live_orders = store.lock().unwrap().get_live_orders(&w.public_key());
+                !live_orders.is_empty() || (config.flatten_on_shutdown && w.open_volume(store) != 0)
+            })
+            .map(|w| w.public_key())
+            .collect();
+
+        if pending.is_empty() {
+            info!("all orders cancelled and positions closed, shutdown complete");
+            return;
+        }
+
+        if time::Instant::now() >= deadline {
+            warn!(
+                "shutdown timeout reached, wallets still have orders or positions: {:?}",
+                pending,
+            );
+            return;
+        }
+
+        time::sleep(SHUTDOWN_POLL_INTERVAL).await;
+    }
 }
 
 async fn run_strategy(
-    w1: &mut Transact,
-    w2: &mut Transact,
-    market: String,
+    strategy: &mut dyn Strategy,
+    wallets: &mut [Wallet],
+    config: &StrategyConfig,
     store: Arc<Mutex<VegaStore>>,
     rp: Arc<Mutex<RefPrice>>,
-    mut default_trade_size: i64,
-) {
+    cycle: u64,
+    last_orders: &mut Option<(String, Vec<usize>)>,
+) -> Decision {
     info!("executing trading strategy...");
-    let mkt = store.lock().unwrap().get_market();
-    let asset = store.lock().unwrap().get_asset(get_asset(&mkt));
+    // nothing reaches the datanode in dry run mode
+    if let Some((reference, traded)) = last_orders.take() {
+        if !wallets[0].is_dry_run() {
+            log_orders_outcome(&store, wallets, &reference, &traded);
+        }
+    }
 
+    let snapshot = get_snapshot(&store, &rp, wallets, cycle, config.min_general_balance);
     info!(
         "updating quotes for {}",
-        mkt.tradable_instrument
+        snapshot
+            .market
+            .tradable_instrument
             .as_ref()
             .unwrap()
             .instrument
@@ -90,207 +368,204 @@ async fn run_strategy(
             .unwrap()
             .name
     );
-
-    default_trade_size = ((rand::random::<u64>() % default_trade_size as u64) + 1) as i64;
-    info!("selected trade size: {}", default_trade_size,);
-
-    let d = Decimals::new(&mkt, &asset);
-
-    let (best_bid, best_ask) = rp.lock().unwrap().get();
-    let mid_price = (best_ask + best_bid) / 2.;
     info!(
         "new reference prices: bestBid({}), bestAsk({}), midPrice({})",
-        best_bid, best_ask, mid_price,
+        snapshot.ref_bid,
+        snapshot.ref_ask,
+        snapshot.ref_mid_price(),
     );
 
-    let md = store.lock().unwrap().get_market_data();
-    // let price = BigUint::from_f64(d.to_market_price_precision(mid_price)).unwrap();
-    let md_bid = BigUint::parse_bytes(md.best_bid_price.as_bytes(), 10).unwrap();
-    let md_ask = BigUint::parse_bytes(md.best_offer_price.as_bytes(), 10).unwrap();
-    let md_mid_price = (md_ask.clone() + md_bid.clone()) / BigUint::from_i64(2).unwrap();
-    info!(
-        "new vega reference prices: bestBid({}), bestAsk({}), midPrice({})",
-        md_bid.to_string(),
-        md_ask.to_string(),
-        md_mid_price,
-    );
-
-    if best_ask == 0. || best_bid == 0. {
+    if snapshot.ref_ask == 0. || snapshot.ref_bid == 0. {
         info!("reference price are not up to date yet");
-        return;
+        return Decision::skipped(cycle, "ref_price_not_ready");
     }
 
-    let w1_position_size = match store.lock().unwrap().get_position(&*w1.public_key()) {
-        Some(p) => p.open_volume,
-        None => 0,
-    };
-
-    let w2_position_size = match store.lock().unwrap().get_position(&*w2.public_key()) {
-        Some(p) => p.open_volume,
-        None => 0,
-    };
-
-    info!("wallet 1 open volume: {}", w1_position_size);
-    info!("wallet 2 open volume: {}", w2_position_size);
-
-    let (w1_order_size, w2_order_size, is_market) =
-        get_order_sizes(w1_position_size, w2_position_size, default_trade_size);
-
-    info!("wallet 1 order size: {}", w1_order_size);
-    info!("wallet 2 order size: {}", w2_order_size);
-    info!("submitting market orders: {}", is_market);
+    match snapshot.ref_price_age {
+        Some(age) if age <= config.max_ref_price_age => {}
+        _ => {
+            info!(
+                "reference price is stale (last update {:?} ago, max age {:?}), skipping",
+                snapshot.ref_price_age.unwrap_or_default(),
+                config.max_ref_price_age,
+            );
+            return Decision::skipped(cycle, "ref_price_stale");
+        }
+    }
 
-    // let price_in_m_precision = BigUint::from_f64(d.to_market_price_precision(mid_price)).unwrap();
+    for w in snapshot.wallets.iter() {
+        info!("{} open volume: {}", w.name, w.open_volume);
+        info!(
+            "{} balances: general({}), margin({}), bond({})",
+            w.name, w.general_balance, w.margin_balance, w.bond_balance,
+        );
+
+        if w.low_balance {
+            info!(
+                "{} general balance {} is below the minimum of {}, not trading",
+                w.name,
+                w.general_balance,
+                config.min_general_balance.unwrap_or_default(),
+            );
+        }
+    }
 
-    // info!(
-    //     "price in market decimal: {}",
-    //     price_in_m_precision.to_string(),
-    // );
+    let (price, batches) = match strategy.decide(config, &snapshot) {
+        Intent::Skip(reason) => return Decision::skipped(cycle, reason),
+        Intent::Trade { price, batches } => (price, batches),
+    };
 
-    let batch_w1 = Command::BatchMarketInstructions(get_batch(
-        market.clone(),
-        md_mid_price.to_string(),
-        w1_order_size,
-        is_market,
-    ));
-    let batch_w2 = Command::BatchMarketInstructions(get_batch(
-        market.clone(),
-        md_mid_price.to_string(),
-        w2_order_size,
-        is_market,
+    let mut order_sizes = BTreeMap::new();
+    *last_orders = Some((
+        order_reference(cycle),
+        batches.iter().map(|b| b.wallet).collect(),
     ));
+    for b in batches.into_iter() {
+        let w = &mut wallets[b.wallet];
+        order_sizes.insert(w.public_key(), b.size);
+        w.send_batch(&format!("w{}", b.wallet + 1), b.batch).await;
+    }
 
-    if w1_order_size > 0 {
-        match w1.send(batch_w1).await {
-            Ok(o) => info!("w1 result: {:?}", o),
-            Err(e) => info!("w1 transaction error: {:?}", e),
-        };
+    return Decision::traded(cycle, price, order_sizes);
+}
 
-        match w2.send(batch_w2).await {
-            Ok(o) => info!("w2 result: {:?}", o),
-            Err(e) => info!("w2 transaction error: {:?}", e),
-        };
-    } else {
-        match w2.send(batch_w2).await {
-            Ok(o) => info!("w2 result: {:?}", o),
-            Err(e) => info!("w2 transaction error: {:?}", e),
-        };
-        match w1.send(batch_w1).await {
-            Ok(o) => info!("w1 result: {:?}", o),
-            Err(e) => info!("w1 transaction error: {:?}", e),
-        };
-    }
+// read-only copy of the state of the market, the reference
+// price and the wallets for this cycle
+fn get_snapshot(
+    store: &Arc<Mutex<VegaStore>>,
+    rp: &Arc<Mutex<RefPrice>>,
+    wallets: &[Wallet],
+    cycle: u64,
+    min_general_balance: Option<f64>,
+) -> Snapshot {
+    let market = store.lock().unwrap().get_market();
+    let asset = store.lock().unwrap().get_asset(get_asset(&market));
+    let market_data = store.lock().unwrap().get_market_data();
+    let decimals = Decimals::new(&market, &asset);
+
+    let (ref_bid, ref_ask, ref_mid, ref_price_age) = {
+        let rp = rp.lock().unwrap();
+        let (bid, ask) = rp.get();
+        (bid, ask, rp.mid_price(), rp.age())
+    };
+
+    let wallets = wallets
+        .iter()
+        .enumerate()
+        .map(|(i, w)| {
+            let pubkey = w.public_key();
+            let balance = |typ| get_pubkey_balance(store, &pubkey, &asset.id, typ, &decimals);
+            let general_balance = balance(AccountType::General);
+            WalletState {
+                name: format!("wallet {}", i + 1),
+                open_volume: w.open_volume(store),
+                general_balance,
+                margin_balance: balance(AccountType::Margin),
+                bond_balance: balance(AccountType::Bond),
+                low_balance: min_general_balance.is_some_and(|min| general_balance < min),
+                pubkey: pubkey.clone(),
+            }
+        })
+        .collect();
+
+    return Snapshot {
+        cycle,
+        market,
+        market_data,
+        asset,
+        decimals,
+        ref_bid,
+        ref_ask,
+        ref_mid,
+        ref_price_age,
+        wallets,
+    };
+}
 
-    // let (open_volume, aep) =
-    //     volume_and_average_entry_price(&d, &store.lock().unwrap().get_position());
-
-    // let mut submissions = get_order_submission(&d, best_bid, Side::Buy, market.clone(), bid_volume);
-    // submissions.append(&mut get_order_submission(
-    //     &d,
-    //     best_ask,
-    //     Side::Sell,
-    //     market.clone(),
-    //     offer_volume,
-    // ));
-
-    // let batch = BatchMarketInstructions {
-    //     cancellations: vec![OrderCancellation {
-    //         market_id: market.clone(),
-    //         order_id: "".to_string(),
-    //     }],
-    //     amendments: vec![],
-    //     submissions,
-    // };
-
-    // info!("batch submission: {:?}", batch);
-    // clt.send(batch).await.unwrap();
+// reference set on the orders submitted during a strategy cycle
+pub fn order_reference(cycle: u64) -> String {
+    return format!("nice-price-bot-{}", cycle);
 }
 
-fn get_batch(
-    market_id: String,
-    price: String,
-    mut size: i64,
-    is_market: bool,
-) -> BatchMarketInstructions {
-    let mut side = Side::Buy;
-    if size < 0 {
-        side = Side::Sell;
-        size = -size;
+// log the outcome of the orders sent by the given wallets
+fn log_orders_outcome(
+    store: &Arc<Mutex<VegaStore>>,
+    wallets: &[Wallet],
+    reference: &str,
+    traded: &[usize],
+) {
+    for i in traded.iter().copied() {
+        let w = &wallets[i];
+        let name = format!("w{}", i + 1);
+        let pubkey = w.public_key();
+        match store
+            .lock()
+            .unwrap()
+            .get_last_order_by_reference(&pubkey, reference)
+        {
+            Some(o) => info!(
+                "{} order {} from previous cycle: status({}), filled({}/{}), reason({})",
+                name,
+                o.id,
+                OrderStatus::try_from(o.status)
+                    .map(|s| s.as_str_name())
+                    .unwrap_or("STATUS_UNKNOWN"),
+                o.size - o.remaining,
+                o.size,
+                o.reason
+                    .and_then(|r| OrderError::try_from(r).ok())
+                    .map(|r| r.as_str_name())
+                    .unwrap_or("NONE"),
+            ),
+            None => info!(
+                "{} order from previous cycle ({}) not seen by the datanode",
+                name, reference
+            ),
+        }
     }
-    let (tif, typ, price) = match is_market {
-        true => (TimeInForce::Ioc, Type::Market, "".to_string()),
-        false => (TimeInForce::Gfn, Type::Limit, price),
-    };
+}
 
+fn get_close_batch(market_id: String) -> BatchMarketInstructions {
     return BatchMarketInstructions {
         cancellations: vec![OrderCancellation {
             order_id: "".to_string(),
             market_id: market_id.clone(),
         }],
         amendments: vec![],
-        submissions: vec![OrderSubmission {
-            expires_at: 0,
-            market_id: market_id.clone(),
-            pegged_order: None,
-            price: price,
-            size: size as u64,
-            reference: "".to_string(),
-            side: side.into(),
-            time_in_force: tif.into(),
-            r#type: typ.into(),
-            reduce_only: false,
-            post_only: false,
-            iceberg_opts: None,
-        }],
+        submissions: vec![],
         stop_orders_cancellation: vec![],
         stop_orders_submission: vec![],
     };
 }
 
-fn get_close_batch(market_id: String) -> BatchMarketInstructions {
+// reduce only market order closing the given open volume
+fn get_flatten_batch(market_id: String, open_volume: i64) -> BatchMarketInstructions {
+    let side = match open_volume > 0 {
+        true => Side::Sell,
+        false => Side::Buy,
+    };
+
     return BatchMarketInstructions {
-        cancellations: vec![OrderCancellation {
-            order_id: "".to_string(),
+        cancellations: vec![],
+        amendments: vec![],
+        submissions: vec![OrderSubmission {
+            expires_at: 0,
             market_id: market_id.clone(),
+            pegged_order: None,
+            price: "".to_string(),
+            size: open_volume.unsigned_abs(),
+            reference: "nice-price-bot-shutdown".to_string(),
+            side: side.into(),
+            time_in_force: TimeInForce::Ioc.into(),
+            r#type: Type::Market.into(),
+            reduce_only: true,
+            post_only: false,
+            iceberg_opts: None,
         }],
-        amendments: vec![],
-        submissions: vec![],
         stop_orders_cancellation: vec![],
         stop_orders_submission: vec![],
     };
 }
 
-fn get_order_sizes(
-    w1_position_size: i64,
-    w2_position_size: i64,
-    default_trade_size: i64,
-) -> (i64, i64, bool) {
-    match (w1_position_size, w2_position_size) {
-        (0, 0) => return (-default_trade_size, default_trade_size, false),
-        (0, v) => {
-            if v > 0 {
-                return (default_trade_size, -default_trade_size, false);
-            }
-            return (-default_trade_size, default_trade_size, false);
-        }
-        (v, 0) => {
-            if v > 0 {
-                return (-default_trade_size, default_trade_size, false);
-            }
-            return (default_trade_size, -default_trade_size, false);
-        }
-        (v1, v2) if v1 > 0 && v2 > 0 => {
-            return (-default_trade_size, -default_trade_size, true);
-        }
-        (v1, v2) if v1 > 0 && v2 < 0 => return (-default_trade_size, default_trade_size, false),
-        (v1, v2) if v1 < 0 && v2 < 0 => {
-            return (default_trade_size, default_trade_size, true);
-        }
-        (v1, v2) if v1 < 0 && v2 > 0 => return (default_trade_size, -default_trade_size, false),
-        _ => unreachable!("all case should be covered, bad bad bad"),
-    }
-}
-
 // fn get_order_submission(
 //     d: &Decimals,
 //     ref_price: f64,
@@ -337,23 +612,26 @@ fn get_order_sizes(
 //     return orders;
 // }
 
-// fn get_pubkey_balance(
-//     store: Arc<Mutex<VegaStore>>,
-//     pubkey: String,
-//     asset_id: String,
-//     d: &Decimals,
-// ) -> f64 {
-//     d.from_asset_precision(store.lock().unwrap().get_accounts().iter().fold(
-//         0f64,
-//         |balance, acc| {
-//             if acc.asset != asset_id || acc.owner != pubkey {
-//                 balance
-//             } else {
-//                 balance + acc.balance.parse::<f64>().unwrap()
-//             }
-//         },
-//     ))
-// }
+// return the balance of all the accounts of the given type
+// owned by pubkey for the asset, in asset units.
+fn get_pubkey_balance(
+    store: &Arc<Mutex<VegaStore>>,
+    pubkey: &str,
+    asset_id: &str,
+    account_type: AccountType,
+    d: &Decimals,
+) -> f64 {
+    d.from_asset_precision(store.lock().unwrap().get_accounts().iter().fold(
+        0f64,
+        |balance, acc| {
+            if acc.asset != asset_id || acc.owner != pubkey || acc.r#type != account_type as i32 {
+                balance
+            } else {
+                balance + acc.balance.parse::<f64>().unwrap_or(0.)
+            }
+        },
+    ))
+}
 
 // // return vol, aep
 // fn volume_and_average_entry_price(d: &Decimals, pos: &Option<Position>) -> (f64, f64) {
@@ -370,29 +648,34 @@ fn get_order_sizes(
 // }
 
 fn get_asset(mkt: &Market) -> String {
+    // the product of the markets is checked at startup
+    return settlement_asset(mkt).expect("spot market not supported");
+}
+
+/// Settlement asset of the market, None if its product is not supported.
+pub fn settlement_asset(mkt: &Market) -> Option<String> {
     match mkt
-        .clone()
         .tradable_instrument
-        .unwrap()
+        .as_ref()?
         .instrument
-        .unwrap()
+        .as_ref()?
         .product
-        .unwrap()
+        .as_ref()?
     {
-        Product::Future(f) => f.settlement_asset,
-        Product::Spot(_) => unimplemented!("spot market not supported"),
-        Product::Perpetual(f) => f.settlement_asset,
+        Product::Future(f) => Some(f.settlement_asset.clone()),
+        Product::Spot(_) => None,
+        Product::Perpetual(f) => Some(f.settlement_asset.clone()),
     }
 }
 
-struct Decimals {
+pub struct Decimals {
     position_factor: f64,
     price_factor: f64,
     asset_factor: f64,
 }
 
 impl Decimals {
-    fn new(mkt: &Market, asset: &Asset) -> Decimals {
+    pub fn new(mkt: &Market, asset: &Asset) -> Decimals {
         return Decimals {
             position_factor: (10_f64).powf(mkt.position_decimal_places as f64),
             price_factor: (10_f64).powf(mkt.decimal_places as f64),
@@ -400,23 +683,23 @@ impl Decimals {
         };
     }
 
-    fn from_asset_precision(&self, amount: f64) -> f64 {
+    pub fn from_asset_precision(&self, amount: f64) -> f64 {
         return amount / self.asset_factor;
     }
 
-    fn from_market_price_precision(&self, price: f64) -> f64 {
+    pub fn from_market_price_precision(&self, price: f64) -> f64 {
         return price / self.price_factor;
     }
 
-    fn from_market_position_precision(&self, position: f64) -> f64 {
+    pub fn from_market_position_precision(&self, position: f64) -> f64 {
         return position / self.position_factor;
     }
 
-    fn to_market_price_precision(&self, price: f64) -> f64 {
+    pub fn to_market_price_precision(&self, price: f64) -> f64 {
         return price * self.price_factor;
     }
 
-    fn to_market_position_precision(&self, position: f64) -> f64 {
+    pub fn to_market_position_precision(&self, position: f64) -> f64 {
         return position * self.position_factor;
     }
 }
@@ -488,3 +771,143 @@ impl Decimals {
 //     info!("batch submission: {:?}", batch);
 //     clt.send(batch).await.unwrap();
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_datanode::{self, account};
+    use crate::test_util::strategy_config as config;
+
+    fn ref_price(bid: f64, ask: f64) -> Arc<Mutex<RefPrice>> {
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        if bid > 0. && ask > 0. {
+            rp.lock().unwrap().set(bid, ask, 0);
+        }
+        return rp;
+    }
+
+    async fn run(
+        config: &StrategyConfig,
+        rp: Arc<Mutex<RefPrice>>,
+    ) -> (Decision, Vec<Wallet>, Arc<Mutex<VegaStore>>) {
+        let (_, store, _) = mock_datanode::store(mock_datanode::script()).await;
+        let mut wallets = vec![Wallet::simulated("w1"), Wallet::simulated("w2")];
+        let mut strategy = config.strategy.build();
+        let mut last_orders = None;
+        let decision = run_strategy(
+            strategy.as_mut(),
+            &mut wallets,
+            config,
+            store.clone(),
+            rp,
+            1,
+            &mut last_orders,
+        )
+        .await;
+        return (decision, wallets, store);
+    }
+
+    #[tokio::test]
+    async fn crosses_at_the_vega_mid() {
+        // the script book is 300000 / 300100, in market precision
+        let (decision, wallets, store) = run(&config(), ref_price(30000., 30010.)).await;
+
+        assert!(decision.traded, "skipped: {}", decision.reason);
+        assert_eq!(decision.price, Some("300050".to_string()));
+        let sizes: Vec<i64> = decision.order_sizes.values().copied().collect();
+        assert_eq!(sizes.iter().sum::<i64>(), 0);
+        assert_eq!(sizes.iter().map(|s| s.abs()).max(), Some(1));
+        for w in wallets.iter() {
+            assert_eq!(w.open_volume(&store), decision.order_sizes[&w.public_key()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_until_the_reference_price_is_received() {
+        let (decision, wallets, store) = run(&config(), ref_price(0., 0.)).await;
+
+        assert!(!decision.traded);
+        assert_eq!(decision.reason, "ref_price_not_ready");
+        assert!(wallets.iter().all(|w| w.open_volume(&store) == 0));
+    }
+
+    #[tokio::test]
+    async fn skips_when_the_vega_mid_is_out_of_band() {
+        let mut config = config();
+        config.max_ref_price_deviation_bps = Some(50);
+        // 1% above the vega mid
+        let (decision, _, _) = run(&config, ref_price(30300., 30310.)).await;
+
+        assert!(!decision.traded);
+        assert_eq!(decision.reason, "out_of_band");
+    }
+
+    #[tokio::test]
+    async fn clamps_to_the_band_when_configured() {
+        let mut config = config();
+        config.max_ref_price_deviation_bps = Some(50);
+        config.out_of_band_action = OutOfBandAction::Clamp;
+        let (decision, _, _) = run(&config, ref_price(30300., 30310.)).await;
+
+        // 303050 - 0.5%
+        assert!(decision.traded, "skipped: {}", decision.reason);
+        assert_eq!(decision.price, Some("301535".to_string()));
+    }
+
+    #[tokio::test]
+    async fn only_trades_the_wallets_above_the_minimum_balance() {
+        let (_, store, _) = mock_datanode::store(mock_datanode::script()).await;
+        // in asset precision, 6 decimals
+        store.lock().unwrap().save_accounts(vec![
+            account("w1", AccountType::General, "2000000000"),
+            account("w2", AccountType::General, "999999999"),
+            account("w2", AccountType::Margin, "5000000000"),
+            account("w3", AccountType::General, "1000000000"),
+        ]);
+        let mut wallets = vec![
+            Wallet::simulated("w1"),
+            Wallet::simulated("w2"),
+            Wallet::simulated("w3"),
+        ];
+        let mut config = config();
+        config.min_general_balance = Some(1000.);
+        let rp = ref_price(30000., 30010.);
+
+        let snapshot = get_snapshot(&store, &rp, &wallets, 1, config.min_general_balance);
+        let low: Vec<bool> = snapshot.wallets.iter().map(|w| w.low_balance).collect();
+        assert_eq!(low, vec![false, true, false]);
+        assert_eq!(snapshot.wallets[1].general_balance, 999.999999);
+
+        let mut strategy = config.strategy.build();
+        for cycle in 1..=3 {
+            let decision = run_strategy(
+                strategy.as_mut(),
+                &mut wallets,
+                &config,
+                store.clone(),
+                rp.clone(),
+                cycle,
+                &mut None,
+            )
+            .await;
+            assert!(decision.traded, "skipped: {}", decision.reason);
+            let traded: Vec<&str> = decision.order_sizes.keys().map(|k| k.as_str()).collect();
+            assert_eq!(traded, vec!["w1", "w3"]);
+        }
+
+        // none left to trade against
+        config.min_general_balance = Some(1500.);
+        let decision = run_strategy(
+            strategy.as_mut(),
+            &mut wallets,
+            &config,
+            store.clone(),
+            rp,
+            4,
+            &mut None,
+        )
+        .await;
+        assert!(!decision.traded);
+        assert_eq!(decision.reason, "low_balance");
+    }
+}
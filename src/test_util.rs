@@ -0,0 +1,47 @@
+// Helpers shared by the tests of the modules.
+
+use std::future::Future;
+use std::time::Duration;
+use tokio::time;
+
+use crate::mock_datanode::MARKET_ID;
+use crate::strategy2::{
+    BookFallback, CounterpartySelection, OutOfBandAction, PricingMode, StrategyConfig, StrategyKind,
+};
+
+/// Poll the condition until it is true, panics after 10 seconds.
+pub async fn wait_for<F: Fn() -> bool>(cond: F) {
+    let deadline = time::Instant::now() + Duration::from_secs(10);
+    while !cond() {
+        if time::Instant::now() >= deadline {
+            panic!("condition not met after 10 seconds");
+        }
+        time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+pub async fn with_timeout<F: Future>(f: F) -> F::Output {
+    return time::timeout(Duration::from_secs(10), f)
+        .await
+        .expect("timed out after 10 seconds");
+}
+
+/// The default strategy on the market of the mock datanode, trading
+/// a size of 1 every second without any of the optional checks.
+pub fn strategy_config() -> StrategyConfig {
+    return StrategyConfig {
+        strategy: StrategyKind::CrossMid,
+        market: MARKET_ID.to_string(),
+        trade_size: 1,
+        submission_rate: 1,
+        max_ref_price_age: Duration::from_secs(10),
+        max_ref_price_deviation_bps: None,
+        out_of_band_action: OutOfBandAction::Skip,
+        pricing_mode: PricingMode::VegaMid,
+        one_sided_book_fallback: BookFallback::Skip,
+        counterparty_selection: CounterpartySelection::Rotation,
+        min_general_balance: None,
+        flatten_on_shutdown: false,
+        shutdown_timeout: Duration::from_secs(1),
+    };
+}
@@ -0,0 +1,223 @@
+use futures::future::BoxFuture;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time;
+use vega_protobufs::vega::{product_data, MarketData};
+
+use crate::reference::{Error, RefPrice, ReferenceFeed};
+use crate::strategy2::settlement_asset;
+use crate::vega_store2::VegaStore;
+
+// the market data is streamed into the store, it is read at this interval
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Price of the vega market data used as a reference price.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VegaPrice {
+    /// Mark price of the market
+    MarkPrice,
+    /// Time weighted average of the external price oracle, perpetuals only
+    OracleTwap,
+}
+
+/// A reference feed reading the market data of the store, without any
+/// connection to an exchange. Its only symbol is the ID of the market.
+pub struct VegaFeed {
+    store: Arc<Mutex<VegaStore>>,
+    price: VegaPrice,
+}
+
+impl VegaFeed {
+    pub fn new(store: Arc<Mutex<VegaStore>>, price: VegaPrice) -> VegaFeed {
+        return VegaFeed { store, price };
+    }
+}
+
+impl ReferenceFeed for VegaFeed {
+    fn name(&self) -> &'static str {
+        return "vega";
+    }
+
+    fn start(
+        self: Box<Self>,
+        feeds: HashMap<String, Arc<Mutex<RefPrice>>>,
+        shutdown: watch::Receiver<bool>,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        return Box::pin(follow(self.store, self.price, feeds, shutdown));
+    }
+}
+
+// update the reference price from the market data until shutdown, it is
+// only updated when new market data is received, so it becomes stale
+// while the market data stream is down.
+async fn follow(
+    store: Arc<Mutex<VegaStore>>,
+    price: VegaPrice,
+    feeds: HashMap<String, Arc<Mutex<RefPrice>>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<(), Error> {
+    let market = store.lock().unwrap().get_market().id;
+    let mut rps = vec![];
+    for (symbol, rp) in feeds.into_iter() {
+        match symbol == market {
+            true => rps.push(rp),
+            false => warn!(
+                "vega feed only prices market {}, ignoring {}",
+                market, symbol
+            ),
+        }
+    }
+    let decimal_places = decimal_places(&store.lock().unwrap(), price);
+    let mut interval = time::interval(POLL_INTERVAL);
+    let mut last_timestamp = None;
+    let mut warned = false;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => {
+                info!("shutting down vega feed");
+                return Ok(());
+            }
+        }
+
+        let md = store.lock().unwrap().get_market_data();
+        if last_timestamp == Some(md.timestamp) {
+            continue;
+        }
+        last_timestamp = Some(md.timestamp);
+
+        match get_price(price, &md) {
+            Some(p) => {
+                // the reference prices are not in the vega precision
+                let p = p / 10_f64.powi(decimal_places as i32);
+                let event_time = (md.timestamp / 1_000_000) as u64;
+                for rp in rps.iter() {
+                    rp.lock().unwrap().set(p, p, event_time);
+                }
+                warned = false;
+            }
+            // only warn once, the market data is updated every block
+            None if !warned => {
+                warn!("no {:?} in the market data of {}", price, md.market);
+                warned = true;
+            }
+            None => {}
+        }
+    }
+}
+
+// decimal places of the price, the oracle prices of the perpetuals are
+// scaled to the decimals of the settlement asset, not of the market
+fn decimal_places(store: &VegaStore, price: VegaPrice) -> u64 {
+    let market = store.get_market();
+    return match price {
+        VegaPrice::MarkPrice => market.decimal_places,
+        VegaPrice::OracleTwap => settlement_asset(&market)
+            .and_then(|asset| store.get_asset(asset).details)
+            .map_or(0, |d| d.decimals),
+    };
+}
+
+// the price in vega precision, None if not set
+fn get_price(price: VegaPrice, md: &MarketData) -> Option<f64> {
+    let value = match price {
+        VegaPrice::MarkPrice => &md.mark_price,
+        VegaPrice::OracleTwap => match md.product_data.as_ref().and_then(|d| d.data.as_ref()) {
+            Some(product_data::Data::PerpetualData(p)) => &p.external_twap,
+            _ => return None,
+        },
+    };
+    return value
+        .parse::<f64>()
+        .ok()
+        .filter(|p| p.is_finite() && *p > 0.);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_datanode::{self, MARKET_ID};
+    use crate::test_util::{wait_for, with_timeout};
+    use tokio::task::JoinHandle;
+    use vega_protobufs::vega::{PerpetualData, ProductData};
+
+    struct Feed {
+        store: Arc<Mutex<VegaStore>>,
+        rp: Arc<Mutex<RefPrice>>,
+        handle: JoinHandle<Result<(), Error>>,
+        // the feed stops once dropped
+        shutdown: watch::Sender<bool>,
+    }
+
+    async fn start_feed(price: VegaPrice) -> Feed {
+        let (_, store, _) = mock_datanode::store(mock_datanode::script()).await;
+        let rp = Arc::new(Mutex::new(RefPrice::new()));
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let feed = Box::new(VegaFeed::new(store.clone(), price));
+        let feeds = HashMap::from([(MARKET_ID.to_string(), rp.clone())]);
+        let handle = tokio::spawn(feed.start(feeds, shutdown_rx));
+        return Feed {
+            store,
+            rp,
+            handle,
+            shutdown,
+        };
+    }
+
+    fn with_timestamp(mut md: MarketData, timestamp_ms: i64) -> MarketData {
+        md.timestamp = timestamp_ms * 1_000_000;
+        return md;
+    }
+
+    #[tokio::test]
+    async fn follows_the_mark_price() {
+        let feed = start_feed(VegaPrice::MarkPrice).await;
+        let (store, rp) = (&feed.store, &feed.rp);
+
+        // 300050 with 1 decimal place
+        wait_for(|| rp.lock().unwrap().mid_price() == 30005.).await;
+        assert_eq!(rp.lock().unwrap().get(), (30005., 30005.));
+
+        let md = mock_datanode::market_data("300000", "300500", "300250");
+        store
+            .lock()
+            .unwrap()
+            .save_market_data(with_timestamp(md, 2));
+        wait_for(|| rp.lock().unwrap().event_time() == 2).await;
+        assert_eq!(rp.lock().unwrap().mid_price(), 30025.);
+
+        feed.shutdown.send(true).unwrap();
+        assert!(with_timeout(feed.handle).await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn follows_the_oracle_twap_of_perpetuals() {
+        let feed = start_feed(VegaPrice::OracleTwap).await;
+        let (store, rp) = (&feed.store, &feed.rp);
+
+        // no product data yet, the reference price is not set
+        time::sleep(POLL_INTERVAL * 2).await;
+        assert!(rp.lock().unwrap().age().is_none());
+
+        let mut md = mock_datanode::market_data("300000", "300100", "300050");
+        md.product_data = Some(ProductData {
+            data: Some(product_data::Data::PerpetualData(PerpetualData {
+                // in asset precision, 6 decimals
+                external_twap: "30100000000".to_string(),
+                internal_twap: "30005000000".to_string(),
+                ..Default::default()
+            })),
+        });
+        store
+            .lock()
+            .unwrap()
+            .save_market_data(with_timestamp(md, 3));
+        wait_for(|| rp.lock().unwrap().event_time() == 3).await;
+        assert_eq!(rp.lock().unwrap().mid_price(), 30100.);
+    }
+}
@@ -1,8 +1,13 @@
-use log::{error, info};
-use std::collections::HashMap;
+use log::{error, info, warn};
+use num_bigint::BigUint;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
 use std::error::Error as StdError;
 use std::fmt;
+use std::future::Future;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::time;
 use tokio_stream::StreamExt;
 use tonic;
 use vega_protobufs::datanode::api::v2::GetLatestMarketDataRequest;
@@ -10,18 +15,89 @@ use vega_protobufs::vega::MarketData;
 
 use vega_protobufs::{
     datanode::api::v2::{
-        trading_data_service_client::TradingDataServiceClient, GetMarketRequest, ListAssetsRequest,
-        ObserveMarketsDataRequest, ObservePositionsRequest,
+        trading_data_service_client::TradingDataServiceClient, AccountBalance, GetMarketRequest,
+        ListAssetsRequest, ObserveAccountsRequest, ObserveMarketsDataRequest, ObserveOrdersRequest,
+        ObservePositionsRequest, ObserveTradesRequest,
     },
-    vega::{Asset, Market, Position},
+    vega::{order::Status, Asset, Fee, Market, Order, Position, Side, Trade},
 };
 
+use crate::backoff::Backoff;
+use crate::journal::Journal;
+use crate::metrics::METRICS;
+
+const RESUBSCRIBE_INITIAL_DELAY: Duration = Duration::from_millis(500);
+const RESUBSCRIBE_MAX_DELAY: Duration = Duration::from_secs(30);
+// a stream which stayed up this long is considered healthy,
+// the backoff restarts from scratch after it drops.
+const HEALTHY_STREAM_AGE: Duration = Duration::from_secs(60);
+// number of orders kept in the store, the oldest terminated
+// orders are dropped past this.
+const MAX_ORDERS: usize = 10_000;
+// number of fills kept in the store
+const MAX_FILLS: usize = 1_000;
+
+/// A trade involving one of the wallets of the bot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Fill {
+    pub trade_id: String,
+    pub market_id: String,
+    pub price: String,
+    pub size: u64,
+    pub aggressor: String,
+    pub buyer: String,
+    pub seller: String,
+    // sum of the maker, infrastructure and liquidity fees
+    pub buyer_fee: String,
+    pub seller_fee: String,
+    // nanoseconds since epoch
+    pub timestamp: i64,
+}
+
+impl From<&Trade> for Fill {
+    fn from(t: &Trade) -> Self {
+        return Fill {
+            trade_id: t.id.clone(),
+            market_id: t.market_id.clone(),
+            price: t.price.clone(),
+            size: t.size,
+            aggressor: Side::try_from(t.aggressor)
+                .map(|s| s.as_str_name())
+                .unwrap_or("SIDE_UNSPECIFIED")
+                .to_string(),
+            buyer: t.buyer.clone(),
+            seller: t.seller.clone(),
+            buyer_fee: total_fee(&t.buyer_fee),
+            seller_fee: total_fee(&t.seller_fee),
+            timestamp: t.timestamp,
+        };
+    }
+}
+
+fn total_fee(fee: &Option<Fee>) -> String {
+    let parse = |f: &str| BigUint::parse_bytes(f.as_bytes(), 10).unwrap_or_default();
+    return match fee {
+        Some(f) => (parse(&f.maker_fee) + parse(&f.infrastructure_fee) + parse(&f.liquidity_fee))
+            .to_string(),
+        None => "0".to_string(),
+    };
+}
+
 pub struct VegaStore {
     market: Market,
     market_data: MarketData,
     positions: HashMap<String, Position>,
+    // key = order ID
+    orders: HashMap<String, Order>,
     // key = asset ID
     assets: HashMap<String, Asset>,
+    // key = (owner, asset ID, market ID, account type)
+    accounts: HashMap<(String, String, String, i32), AccountBalance>,
+    // most recent fills last
+    fills: VecDeque<Fill>,
+    // key = stream name, value = time since which the stream is
+    // connected, None while it is reconnecting
+    streams_health: HashMap<String, Option<SystemTime>>,
 }
 
 impl VegaStore {
@@ -68,6 +144,10 @@ impl VegaStore {
             market,
             assets,
             positions,
+            orders: HashMap::new(),
+            accounts: HashMap::new(),
+            fills: VecDeque::new(),
+            streams_health: HashMap::new(),
         });
     }
 
@@ -100,50 +180,224 @@ impl VegaStore {
     pub fn save_market_data(&mut self, md: MarketData) {
         self.market_data = md
     }
+
+    pub fn get_accounts(&self) -> Vec<AccountBalance> {
+        return self.accounts.values().cloned().collect();
+    }
+
+    pub fn save_accounts(&mut self, accounts: Vec<AccountBalance>) {
+        for a in accounts.into_iter() {
+            let key = (
+                a.owner.clone(),
+                a.asset.clone(),
+                a.market_id.clone(),
+                a.r#type,
+            );
+            self.accounts.insert(key, a);
+        }
+    }
+
+    pub fn get_fills(&self) -> Vec<Fill> {
+        return self.fills.iter().cloned().collect();
+    }
+
+    pub fn save_fills(&mut self, fills: Vec<Fill>) {
+        for f in fills.into_iter() {
+            self.fills.push_back(f);
+        }
+        while self.fills.len() > MAX_FILLS {
+            self.fills.pop_front();
+        }
+    }
+
+    /// Return the orders of a party still resting on the book.
+    pub fn get_live_orders(&self, party_id: &str) -> Vec<Order> {
+        return self
+            .orders
+            .values()
+            .filter(|o| o.party_id == party_id && is_live(o))
+            .cloned()
+            .collect();
+    }
+
+    /// Return the most recent order of a party with the given reference.
+    pub fn get_last_order_by_reference(&self, party_id: &str, reference: &str) -> Option<Order> {
+        return self
+            .orders
+            .values()
+            .filter(|o| o.party_id == party_id && o.reference == reference)
+            .max_by_key(|o| o.created_at)
+            .cloned();
+    }
+
+    pub fn save_orders(&mut self, orders: Vec<Order>) {
+        for o in orders.into_iter() {
+            self.orders.insert(o.id.clone(), o);
+        }
+
+        if self.orders.len() > MAX_ORDERS {
+            let mut terminated: Vec<(i64, String)> = self
+                .orders
+                .values()
+                .filter(|o| !is_live(o))
+                .map(|o| (o.updated_at.max(o.created_at), o.id.clone()))
+                .collect();
+            terminated.sort();
+            for (_, id) in terminated.into_iter().take(self.orders.len() - MAX_ORDERS) {
+                self.orders.remove(&id);
+            }
+        }
+    }
+
+    /// Return the time since which each stream is healthy,
+    /// None for streams currently reconnecting.
+    pub fn get_streams_health(&self) -> HashMap<String, Option<SystemTime>> {
+        return self.streams_health.clone();
+    }
+
+    pub fn set_stream_healthy(&mut self, stream: &str) {
+        self.streams_health
+            .insert(stream.to_string(), Some(SystemTime::now()));
+    }
+
+    pub fn set_stream_unhealthy(&mut self, stream: &str) {
+        self.streams_health.insert(stream.to_string(), None);
+    }
+}
+
+fn is_live(order: &Order) -> bool {
+    return order.status == Status::Active as i32 || order.status == Status::Parked as i32;
 }
 
 pub fn update_forever(
     store: Arc<Mutex<VegaStore>>,
     clt: TradingDataServiceClient<tonic::transport::Channel>,
     market: &str,
-    pubkey1: &str,
-    pubkey2: &str,
+    pubkeys: &[String],
+    journal: Option<Arc<Mutex<Journal>>>,
 ) {
-    tokio::spawn(update_market_data_forever(
-        store.clone(),
-        clt.clone(),
-        market.to_string(),
-    ));
-    tokio::spawn(update_position_forever(
-        store.clone(),
-        clt.clone(),
-        market.to_string(),
-        pubkey1.to_string(),
-    ));
-    tokio::spawn(update_position_forever(
-        store.clone(),
-        clt.clone(),
-        market.to_string(),
-        pubkey2.to_string(),
-    ));
-}
-
-async fn update_market_data_forever(
+    let (s, c, m) = (store.clone(), clt.clone(), market.to_string());
+    let stream_name = format!("market_data:{}", market);
+    let name = stream_name.clone();
+    tokio::spawn(observe_forever(store.clone(), stream_name, move || {
+        observe_market_data(s.clone(), c.clone(), m.clone(), name.clone())
+    }));
+
+    for pubkey in pubkeys.iter() {
+        let (s, c, m, k) = (
+            store.clone(),
+            clt.clone(),
+            market.to_string(),
+            pubkey.to_string(),
+        );
+        let stream_name = format!("positions:{}:{}", market, pubkey);
+        let name = stream_name.clone();
+        tokio::spawn(observe_forever(store.clone(), stream_name, move || {
+            observe_positions(s.clone(), c.clone(), m.clone(), k.clone(), name.clone())
+        }));
+    }
+
+    for pubkey in pubkeys.iter() {
+        let (s, c, k) = (store.clone(), clt.clone(), pubkey.to_string());
+        let stream_name = format!("accounts:{}:{}", market, pubkey);
+        let name = stream_name.clone();
+        tokio::spawn(observe_forever(store.clone(), stream_name, move || {
+            observe_accounts(s.clone(), c.clone(), k.clone(), name.clone())
+        }));
+    }
+
+    let (s, c, m) = (store.clone(), clt.clone(), market.to_string());
+    let parties = pubkeys.to_vec();
+    let stream_name = format!("orders:{}", market);
+    let name = stream_name.clone();
+    tokio::spawn(observe_forever(store.clone(), stream_name, move || {
+        observe_orders(
+            s.clone(),
+            c.clone(),
+            m.clone(),
+            parties.clone(),
+            name.clone(),
+        )
+    }));
+
+    let (s, c, m) = (store.clone(), clt.clone(), market.to_string());
+    let parties = pubkeys.to_vec();
+    let stream_name = format!("trades:{}", market);
+    let name = stream_name.clone();
+    tokio::spawn(observe_forever(store.clone(), stream_name, move || {
+        observe_trades(
+            s.clone(),
+            c.clone(),
+            m.clone(),
+            parties.clone(),
+            journal.clone(),
+            name.clone(),
+        )
+    }));
+}
+
+// keep a stream subscribed forever, resubscribing with
+// backoff every time it fails or ends.
+async fn observe_forever<F, Fut>(store: Arc<Mutex<VegaStore>>, stream_name: String, observe: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<(), Error>>,
+{
+    let mut backoff = Backoff::new(RESUBSCRIBE_INITIAL_DELAY, RESUBSCRIBE_MAX_DELAY);
+    loop {
+        let started_at = Instant::now();
+        let err = match observe().await {
+            Ok(()) => Error::StreamEnded,
+            Err(e) => e,
+        };
+
+        store.lock().unwrap().set_stream_unhealthy(&stream_name);
+        METRICS.inc_stream_reconnects(&stream_name);
+        if started_at.elapsed() >= HEALTHY_STREAM_AGE {
+            backoff.reset();
+        }
+        let delay = backoff.next_delay();
+        warn!(
+            "{} stream interrupted ({}), resubscribing in {:?} (attempt {})",
+            stream_name,
+            err,
+            delay,
+            backoff.attempt(),
+        );
+        time::sleep(delay).await;
+    }
+}
+
+async fn observe_market_data(
     store: Arc<Mutex<VegaStore>>,
     mut clt: TradingDataServiceClient<tonic::transport::Channel>,
     market: String,
-) {
+    stream_name: String,
+) -> Result<(), Error> {
     // use vega_protobufs::datanode::api::v2::observe_markets_data_response=
     info!("starting market_data stream for party: {}...", &*market);
-    let mut stream = match clt
+    let mut stream = clt
         .observe_markets_data(ObserveMarketsDataRequest {
-            market_ids: vec![market],
+            market_ids: vec![market.clone()],
+        })
+        .await?
+        .into_inner();
+
+    // resync as updates may have been missed while disconnected
+    let md = clt
+        .get_latest_market_data(GetLatestMarketDataRequest {
+            market_id: market.clone(),
         })
-        .await
+        .await?
+        .into_inner()
+        .market_data;
     {
-        Ok(s) => s.into_inner(),
-        Err(e) => panic!("{:?}", e),
-    };
+        let mut store = store.lock().unwrap();
+        if let Some(md) = md {
+            store.save_market_data(md);
+        }
+        store.set_stream_healthy(&stream_name);
+    }
 
     while let Some(item) = stream.next().await {
         match item {
@@ -157,33 +411,37 @@ async fn update_market_data_forever(
             }
         }
     }
+
+    return Ok(());
 }
 
-async fn update_position_forever(
+async fn observe_positions(
     store: Arc<Mutex<VegaStore>>,
     mut clt: TradingDataServiceClient<tonic::transport::Channel>,
     market: String,
     pubkey: String,
-) {
+    stream_name: String,
+) -> Result<(), Error> {
     use vega_protobufs::datanode::api::v2::observe_positions_response::Response;
     info!("starting positions stream for party: {}...", &*pubkey);
-    let mut stream = match clt
+    let mut stream = clt
         .observe_positions(ObservePositionsRequest {
             party_id: Some(pubkey),
             market_id: Some(market),
         })
-        .await
-    {
-        Ok(s) => s.into_inner(),
-        Err(e) => panic!("{:?}", e),
-    };
+        .await?
+        .into_inner();
 
     while let Some(item) = stream.next().await {
         match item {
             Ok(resp) => match resp.response {
+                // the datanode always starts the stream with a snapshot
+                // of the positions, which resyncs the store on reconnect.
                 Some(r) => match r {
                     Response::Snapshot(o) => {
-                        store.lock().unwrap().save_positions(o.positions.clone())
+                        let mut store = store.lock().unwrap();
+                        store.save_positions(o.positions.clone());
+                        store.set_stream_healthy(&stream_name);
                     }
                     Response::Updates(o) => {
                         store.lock().unwrap().save_positions(o.positions.clone())
@@ -196,12 +454,146 @@ async fn update_position_forever(
             }
         }
     }
+
+    return Ok(());
+}
+
+async fn observe_orders(
+    store: Arc<Mutex<VegaStore>>,
+    mut clt: TradingDataServiceClient<tonic::transport::Channel>,
+    market: String,
+    parties: Vec<String>,
+    stream_name: String,
+) -> Result<(), Error> {
+    use vega_protobufs::datanode::api::v2::observe_orders_response::Response;
+    info!("starting orders stream for parties: {:?}...", parties);
+    let mut stream = clt
+        .observe_orders(ObserveOrdersRequest {
+            market_ids: vec![market],
+            party_ids: parties,
+            exclude_liquidity: Some(true),
+        })
+        .await?
+        .into_inner();
+
+    while let Some(item) = stream.next().await {
+        match item {
+            Ok(resp) => match resp.response {
+                Some(r) => match r {
+                    Response::Snapshot(o) => {
+                        let mut store = store.lock().unwrap();
+                        store.save_orders(o.orders.clone());
+                        store.set_stream_healthy(&stream_name);
+                    }
+                    Response::Updates(o) => store.lock().unwrap().save_orders(o.orders.clone()),
+                },
+                _ => {}
+            },
+            Err(e) => {
+                error!("could not load orders: {} - {}", e, e.message());
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+async fn observe_accounts(
+    store: Arc<Mutex<VegaStore>>,
+    mut clt: TradingDataServiceClient<tonic::transport::Channel>,
+    pubkey: String,
+    stream_name: String,
+) -> Result<(), Error> {
+    use vega_protobufs::datanode::api::v2::observe_accounts_response::Response;
+    info!("starting accounts stream for party: {}...", &*pubkey);
+    // empty filters, we want all the accounts of the party
+    let mut stream = clt
+        .observe_accounts(ObserveAccountsRequest {
+            market_id: "".to_string(),
+            party_id: pubkey,
+            asset: "".to_string(),
+            r#type: 0,
+        })
+        .await?
+        .into_inner();
+
+    while let Some(item) = stream.next().await {
+        match item {
+            Ok(resp) => match resp.response {
+                Some(r) => match r {
+                    Response::Snapshot(o) => {
+                        let mut store = store.lock().unwrap();
+                        store.save_accounts(o.accounts.clone());
+                        store.set_stream_healthy(&stream_name);
+                    }
+                    Response::Updates(o) => store.lock().unwrap().save_accounts(o.accounts.clone()),
+                },
+                _ => {}
+            },
+            Err(e) => {
+                error!("could not load accounts: {} - {}", e, e.message());
+            }
+        }
+    }
+
+    return Ok(());
+}
+
+async fn observe_trades(
+    store: Arc<Mutex<VegaStore>>,
+    mut clt: TradingDataServiceClient<tonic::transport::Channel>,
+    market: String,
+    parties: Vec<String>,
+    journal: Option<Arc<Mutex<Journal>>>,
+    stream_name: String,
+) -> Result<(), Error> {
+    info!("starting trades stream for parties: {:?}...", parties);
+    let mut stream = clt
+        .observe_trades(ObserveTradesRequest {
+            market_ids: vec![market.clone()],
+            party_ids: parties,
+        })
+        .await?
+        .into_inner();
+    store.lock().unwrap().set_stream_healthy(&stream_name);
+
+    while let Some(item) = stream.next().await {
+        match item {
+            Ok(resp) => {
+                let fills: Vec<Fill> = resp.trades.iter().map(Fill::from).collect();
+                let mkt = store.lock().unwrap().get_market();
+                let price_factor = 10_f64.powi(mkt.decimal_places as i32);
+                let position_factor = 10_f64.powi(mkt.position_decimal_places as i32);
+                for f in fills.iter() {
+                    let price = f.price.parse::<f64>().unwrap_or(0.) / price_factor;
+                    METRICS.add_traded_notional(&market, price * f.size as f64 / position_factor);
+                    info!(
+                        "new fill: price({}), size({}), buyer({}), seller({}), aggressor({})",
+                        f.price, f.size, f.buyer, f.seller, f.aggressor,
+                    );
+                    if let Some(j) = journal.as_ref() {
+                        let mut j = j.lock().unwrap();
+                        if let Err(e) = j.append(f) {
+                            error!("could not write fill to journal {}: {}", j.path(), e);
+                        }
+                    }
+                }
+                store.lock().unwrap().save_fills(fills);
+            }
+            Err(e) => {
+                error!("could not load trades: {} - {}", e, e.message());
+            }
+        }
+    }
+
+    return Ok(());
 }
 
 #[derive(Debug)]
 pub enum Error {
     GrpcTransportError(tonic::transport::Error),
     GrpcError(tonic::Status),
+    StreamEnded,
 }
 
 impl fmt::Display for Error {
@@ -229,6 +621,274 @@ impl Error {
         match self {
             GrpcTransportError(e) => format!("GRPC transport error: {}", e),
             GrpcError(e) => format!("GRPC error: {}", e),
+            StreamEnded => format!("GRPC stream ended"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_datanode::{
+        self, account, accounts_snapshot, market_data, market_data_update, order, orders_snapshot,
+        orders_update, position, positions_snapshot, positions_update, trades, MockDatanode,
+        Script, Subscription, ASSET_ID, MARKET_ID,
+    };
+    use crate::test_util::{wait_for, with_timeout};
+    use vega_protobufs::vega::AccountType;
+
+    const PARTY_1: &str = "2e4f34a38204a2a155be678e670903ed8df96e813700729deacd3daf7e55039e";
+    const PARTY_2: &str = "c1a7e4a07d5e2b2f26e5c4d6b1d0c6d8c9a6b1f1e3a0e1f0c1d2e3f4a5b6c7d8";
+
+    async fn start_store(script: Script) -> (Arc<Mutex<Script>>, Arc<Mutex<VegaStore>>) {
+        let (script, store, clt) = mock_datanode::store(script).await;
+        let pubkeys = [PARTY_1.to_string(), PARTY_2.to_string()];
+        update_forever(store.clone(), clt, MARKET_ID, &pubkeys, None);
+        return (script, store);
+    }
+
+    fn open_volume(store: &Arc<Mutex<VegaStore>>, party: &str) -> Option<i64> {
+        return store
+            .lock()
+            .unwrap()
+            .get_position(party)
+            .map(|p| p.open_volume);
+    }
+
+    fn calls(script: &Arc<Mutex<Script>>, method: &str) -> usize {
+        return *script.lock().unwrap().calls.get(method).unwrap_or(&0);
+    }
+
+    #[tokio::test]
+    async fn new_loads_market_and_assets() {
+        let (_, addr) = MockDatanode::start(mock_datanode::script()).await;
+        let mut clt = mock_datanode::connect(addr).await;
+        let store = with_timeout(VegaStore::new(&mut clt, MARKET_ID))
+            .await
+            .unwrap();
+
+        assert_eq!(store.get_market().id, MARKET_ID);
+        assert_eq!(store.get_market_data().best_bid_price, "300000");
+        let asset = store.get_asset(ASSET_ID.to_string());
+        assert_eq!(asset.details.unwrap().decimals, 6);
+    }
+
+    #[tokio::test]
+    async fn applies_market_data_and_position_updates() {
+        let mut script = mock_datanode::script();
+        script.market_data_streams.push_back(Subscription {
+            messages: vec![Ok(market_data_update(market_data(
+                "300200", "300300", "300250",
+            )))],
+            close: false,
+        });
+        script.positions_streams.insert(
+            PARTY_1.to_string(),
+            VecDeque::from([Subscription {
+                messages: vec![
+                    Ok(positions_snapshot(vec![position(PARTY_1, 2)])),
+                    Ok(positions_update(vec![position(PARTY_1, 5)])),
+                ],
+                close: false,
+            }]),
+        );
+        let (_, store) = start_store(script).await;
+
+        wait_for(|| store.lock().unwrap().get_market_data().mark_price == "300250").await;
+        wait_for(|| open_volume(&store, PARTY_1) == Some(5)).await;
+        assert_eq!(open_volume(&store, PARTY_2), None);
+
+        let health = store.lock().unwrap().get_streams_health();
+        assert!(health[&format!("positions:{}:{}", MARKET_ID, PARTY_1)].is_some());
+        assert!(health[&format!("market_data:{}", MARKET_ID)].is_some());
+    }
+
+    #[tokio::test]
+    async fn positions_stream_resubscribes_after_an_error() {
+        let mut script = mock_datanode::script();
+        script.positions_streams.insert(
+            PARTY_1.to_string(),
+            VecDeque::from([
+                Subscription {
+                    messages: vec![
+                        Ok(positions_snapshot(vec![position(PARTY_1, 1)])),
+                        Err(tonic::Status::unavailable("datanode restarting")),
+                    ],
+                    close: true,
+                },
+                Subscription {
+                    messages: vec![Ok(positions_snapshot(vec![position(PARTY_1, 3)]))],
+                    close: false,
+                },
+            ]),
+        );
+        let (script, store) = start_store(script).await;
+
+        // the snapshot of the new subscription resyncs the position
+        wait_for(|| open_volume(&store, PARTY_1) == Some(3)).await;
+        // one subscription per party, plus the resubscription
+        assert_eq!(calls(&script, "ObservePositions"), 3);
+        let health = store.lock().unwrap().get_streams_health();
+        assert!(health[&format!("positions:{}:{}", MARKET_ID, PARTY_1)].is_some());
+    }
+
+    #[tokio::test]
+    async fn market_data_resyncs_after_the_stream_ends() {
+        let mut script = mock_datanode::script();
+        script.market_data_streams.push_back(Subscription {
+            messages: vec![],
+            close: true,
+        });
+        let (script, store) = start_store(script).await;
+        script.lock().unwrap().market_data = market_data("301000", "301100", "301050");
+
+        wait_for(|| calls(&script, "ObserveMarketsData") >= 2).await;
+        wait_for(|| store.lock().unwrap().get_market_data().mark_price == "301050").await;
+    }
+
+    fn open_with<T>(messages: Vec<T>) -> VecDeque<Subscription<T>> {
+        return VecDeque::from([Subscription {
+            messages: messages.into_iter().map(Ok).collect(),
+            close: false,
+        }]);
+    }
+
+    fn trade(id: &str, price: &str, size: u64) -> Trade {
+        return Trade {
+            id: id.to_string(),
+            market_id: MARKET_ID.to_string(),
+            price: price.to_string(),
+            size,
+            buyer: PARTY_1.to_string(),
+            seller: PARTY_2.to_string(),
+            aggressor: Side::Buy as i32,
+            ..Default::default()
+        };
+    }
+
+    #[tokio::test]
+    async fn applies_the_orders_accounts_and_trades_streams() {
+        let mut script = mock_datanode::script();
+        script.orders_streams = open_with(vec![
+            orders_snapshot(vec![order("o1", PARTY_1, "r1", Status::Active, 1)]),
+            orders_update(vec![
+                order("o1", PARTY_1, "r1", Status::Filled, 1),
+                order("o2", PARTY_2, "r1", Status::Active, 2),
+            ]),
+        ]);
+        script.accounts_streams.insert(
+            PARTY_1.to_string(),
+            open_with(vec![accounts_snapshot(vec![account(
+                PARTY_1,
+                AccountType::General,
+                "1000000000",
+            )])]),
+        );
+        script.trades_streams = open_with(vec![trades(vec![trade("t1", "300050", 2)])]);
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fills.jsonl");
+        let journal = Journal::open(path.to_str().unwrap()).unwrap();
+        let (_, store, clt) = mock_datanode::store(script).await;
+        let pubkeys = [PARTY_1.to_string(), PARTY_2.to_string()];
+        let journal = Some(Arc::new(Mutex::new(journal)));
+        update_forever(store.clone(), clt, MARKET_ID, &pubkeys, journal);
+
+        wait_for(|| store.lock().unwrap().get_live_orders(PARTY_2).len() == 1).await;
+        wait_for(|| store.lock().unwrap().get_fills().len() == 1).await;
+        let store = store.lock().unwrap();
+        assert!(store.get_live_orders(PARTY_1).is_empty());
+        let accounts = store.get_accounts();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].balance, "1000000000");
+        assert_eq!(store.get_fills()[0].trade_id, "t1");
+        let health = store.get_streams_health();
+        assert!(health[&format!("orders:{}", MARKET_ID)].is_some());
+        assert!(health[&format!("accounts:{}:{}", MARKET_ID, PARTY_1)].is_some());
+        assert!(health[&format!("trades:{}", MARKET_ID)].is_some());
+
+        let journal = std::fs::read_to_string(&path).unwrap();
+        let fills: Vec<Fill> = journal
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].trade_id, "t1");
+    }
+
+    #[tokio::test]
+    async fn drops_the_oldest_terminated_orders_past_the_limit() {
+        let (_, store, _) = mock_datanode::store(mock_datanode::script()).await;
+        let mut store = store.lock().unwrap();
+        // the live orders are the oldest ones, they are kept anyway
+        let mut orders = vec![
+            order("live-1", PARTY_1, "r", Status::Active, 0),
+            order("live-2", PARTY_1, "r", Status::Parked, 0),
+        ];
+        for i in 0..MAX_ORDERS {
+            let id = format!("done-{}", i);
+            orders.push(order(&id, PARTY_1, "r", Status::Filled, 1 + i as i64));
         }
+        store.save_orders(orders);
+
+        assert_eq!(store.orders.len(), MAX_ORDERS);
+        assert_eq!(store.get_live_orders(PARTY_1).len(), 2);
+        assert!(!store.orders.contains_key("done-0"));
+        assert!(!store.orders.contains_key("done-1"));
+        assert!(store.orders.contains_key("done-2"));
+
+        // an update of an old order counts as recent
+        let mut update = order("done-2", PARTY_1, "r", Status::Cancelled, 2);
+        update.updated_at = 1 + MAX_ORDERS as i64;
+        store.save_orders(vec![update, order("new", PARTY_1, "r", Status::Filled, 0)]);
+        assert_eq!(store.orders.len(), MAX_ORDERS);
+        assert!(store.orders.contains_key("done-2"));
+        assert!(!store.orders.contains_key("new"));
+    }
+
+    #[tokio::test]
+    async fn returns_the_last_order_of_the_party_by_reference() {
+        let (_, store, _) = mock_datanode::store(mock_datanode::script()).await;
+        let mut store = store.lock().unwrap();
+        store.save_orders(vec![
+            order("o1", PARTY_1, "r1", Status::Filled, 1),
+            order("o2", PARTY_1, "r1", Status::Active, 3),
+            order("o3", PARTY_1, "r2", Status::Active, 4),
+            order("o4", PARTY_2, "r1", Status::Active, 5),
+        ]);
+
+        let last = |party, reference| {
+            store
+                .get_last_order_by_reference(party, reference)
+                .map(|o| o.id)
+        };
+        assert_eq!(last(PARTY_1, "r1"), Some("o2".to_string()));
+        assert_eq!(last(PARTY_1, "r2"), Some("o3".to_string()));
+        assert_eq!(last(PARTY_2, "r1"), Some("o4".to_string()));
+        assert_eq!(last(PARTY_2, "r2"), None);
+    }
+
+    #[test]
+    fn reads_a_fill_from_a_trade() {
+        let mut t = trade("t1", "300050", 2);
+        t.timestamp = 42;
+        t.buyer_fee = Some(Fee {
+            maker_fee: "18446744073709551615".to_string(),
+            infrastructure_fee: "2".to_string(),
+            liquidity_fee: "".to_string(),
+            ..Default::default()
+        });
+        let fill = Fill::from(&t);
+
+        assert_eq!(fill.trade_id, "t1");
+        assert_eq!(fill.market_id, MARKET_ID);
+        assert_eq!(fill.price, "300050");
+        assert_eq!(fill.size, 2);
+        assert_eq!(fill.aggressor, "SIDE_BUY");
+        assert_eq!(fill.buyer, PARTY_1);
+        assert_eq!(fill.seller, PARTY_2);
+        // no overflow, an empty fee counts as 0
+        assert_eq!(fill.buyer_fee, "18446744073709551617");
+        assert_eq!(fill.seller_fee, "0");
+        assert_eq!(fill.timestamp, 42);
     }
 }
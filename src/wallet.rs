@@ -0,0 +1,113 @@
+use log::info;
+use std::sync::{Arc, Mutex};
+use vega_crypto::Transact;
+use vega_protobufs::vega::{
+    commands::v1::{input_data::Command, BatchMarketInstructions},
+    Side,
+};
+
+use crate::{metrics::METRICS, vega_store2::VegaStore};
+
+/// A wallet of the bot. In dry run mode the commands are logged
+/// instead of being sent, and the position of the wallet is simulated
+/// assuming every order is fully filled.
+pub struct Wallet {
+    pubkey: String,
+    // None in dry run mode
+    transact: Option<Transact>,
+    simulated_open_volume: i64,
+}
+
+impl Wallet {
+    pub fn new(transact: Transact, dry_run: bool) -> Wallet {
+        return Wallet {
+            pubkey: transact.public_key(),
+            transact: (!dry_run).then_some(transact),
+            simulated_open_volume: 0,
+        };
+    }
+
+    /// A dry run wallet for the given public key, which
+    /// does not need a connection to a node.
+    pub fn simulated(pubkey: &str) -> Wallet {
+        return Wallet {
+            pubkey: pubkey.to_string(),
+            transact: None,
+            simulated_open_volume: 0,
+        };
+    }
+
+    pub fn public_key(&self) -> String {
+        return self.pubkey.clone();
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        return self.transact.is_none();
+    }
+
+    /// Return the open volume of the wallet, the simulated
+    /// one in dry run mode.
+    pub fn open_volume(&self, store: &Arc<Mutex<VegaStore>>) -> i64 {
+        if self.is_dry_run() {
+            return self.simulated_open_volume;
+        }
+
+        return match store.lock().unwrap().get_position(&self.public_key()) {
+            Some(p) => p.open_volume,
+            None => 0,
+        };
+    }
+
+    // send the batch from the wallet, the result is only logged
+    pub async fn send_batch(&mut self, name: &str, batch: BatchMarketInstructions) {
+        let orders = batch.submissions.len() as u64;
+        let pubkey = self.public_key();
+
+        let transact = match self.transact.as_mut() {
+            Some(transact) => transact,
+            None => {
+                info!("{} dry run, not sending: {:?}", name, batch);
+                self.simulate(&batch);
+                info!(
+                    "{} simulated open volume: {}",
+                    name, self.simulated_open_volume
+                );
+                METRICS.add_orders_simulated(&pubkey, orders);
+                return;
+            }
+        };
+
+        match transact.send(Command::BatchMarketInstructions(batch)).await {
+            Ok(o) => {
+                info!("{} result: {:?}", name, o);
+                METRICS.add_orders_sent(&pubkey, orders);
+            }
+            Err(e) => {
+                info!("{} transaction error: {:?}", name, e);
+                METRICS.inc_transaction_errors(&pubkey);
+            }
+        };
+    }
+
+    fn simulate(&mut self, batch: &BatchMarketInstructions) {
+        for s in batch.submissions.iter() {
+            let size = s.size as i64;
+            let mut delta = match Side::try_from(s.side) {
+                Ok(Side::Buy) => size,
+                Ok(Side::Sell) => -size,
+                _ => 0,
+            };
+
+            // a reduce only order can at most close the position
+            if s.reduce_only {
+                if delta.signum() == self.simulated_open_volume.signum() {
+                    delta = 0;
+                } else if delta.abs() > self.simulated_open_volume.abs() {
+                    delta = -self.simulated_open_volume;
+                }
+            }
+
+            self.simulated_open_volume += delta;
+        }
+    }
+}